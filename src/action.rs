@@ -0,0 +1,64 @@
+use egg::{Analysis, Language, Rewrite};
+
+/// One action of the environment: run a group of rules for up to `iter_limit` egg
+/// iterations. The default action space has one action per rule, run for a single
+/// iteration; macro actions let the planner reason at a coarser granularity, with
+/// shallower trees for deep rewrite chains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    pub name: String,
+    /// Indices into the rules passed to [`crate::run::run_mcts`].
+    pub rules: Vec<usize>,
+    pub iter_limit: usize,
+}
+
+impl Action {
+    /// A single rule for a single iteration.
+    pub fn rule<L: Language, N: Analysis<L>>(rules: &[Rewrite<L, N>], rule: usize) -> Self {
+        Action {
+            name: rules[rule].name.to_string(),
+            rules: vec![rule],
+            iter_limit: 1,
+        }
+    }
+
+    /// A single rule for up to `iter_limit` iterations.
+    pub fn repeat<L: Language, N: Analysis<L>>(
+        rules: &[Rewrite<L, N>],
+        rule: usize,
+        iter_limit: usize,
+    ) -> Self {
+        Action {
+            name: format!("{}*{}", rules[rule].name, iter_limit),
+            rules: vec![rule],
+            iter_limit,
+        }
+    }
+
+    /// Every rule whose name satisfies `pred`, applied together for a single
+    /// iteration.
+    pub fn group<L: Language, N: Analysis<L>>(
+        name: &str,
+        rules: &[Rewrite<L, N>],
+        pred: impl Fn(&str) -> bool,
+    ) -> Self {
+        Action {
+            name: name.to_string(),
+            rules: (0..rules.len())
+                .filter(|&i| pred(rules[i].name.as_str()))
+                .collect(),
+            iter_limit: 1,
+        }
+    }
+
+    /// Run the action for up to `iter_limit` iterations instead.
+    pub fn with_iter_limit(mut self, iter_limit: usize) -> Self {
+        self.iter_limit = iter_limit;
+        self
+    }
+
+    /// The default action space: one action per rule.
+    pub fn per_rule<L: Language, N: Analysis<L>>(rules: &[Rewrite<L, N>]) -> Vec<Self> {
+        (0..rules.len()).map(|i| Action::rule(rules, i)).collect()
+    }
+}
@@ -0,0 +1,335 @@
+use egg::*;
+use rmcts::action::Action;
+use rmcts::domains::{math, simple};
+use rmcts::policy::{ProgressiveWidening, Puct, SelectionPolicy, Ucb1, Ucb1Tuned};
+use rmcts::reward::{IntoReward, RewardFn, RootCost};
+use rmcts::rollout::{
+    EpsilonGreedy, ProportionalValue, RolloutAggregation, RolloutPolicy, RuleWeighted,
+    SkipSaturated, Uniform,
+};
+use rmcts::run::{self, CheckpointMode, MCTSArgs};
+
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Optimise s-expressions with MCTS-guided equality saturation.
+
+Usage: rmcts [OPTIONS] [FILE]...
+
+Reads one expression per line from each FILE, or from stdin if no FILE is
+given or FILE is `-`. Empty lines and lines starting with `;` are skipped.
+
+Options:
+  --lang <simple|math>          language of the input [default: math]
+  --budget <N>                  simulations per planning step, 0 for time-only
+  --planning-time-ms <MS>       wall-clock limit of each planning step
+  --max-sim-step <N>            maximum steps of a rollout
+  --task-timeout-ms <MS>        deadline of each simulation, in milliseconds
+  --rollout <uniform|epsilon-greedy|skip-saturated|weighted>
+                                rollout policy [default: uniform, or weighted
+                                with --rollout-weights]
+  --epsilon <P>                 exploration of epsilon-greedy rollouts [default: 0.1]
+  --rollout-weights <W,W,..>    weights of the weighted rollouts, one per action
+  --rollouts-per-leaf <K>       rollouts from every expanded leaf [default: 1]
+  --rollout-aggregation <mean|max>
+                                how the returns of a leaf are combined
+  --value <SCALE> <FACTOR>      blend rollouts with a value of SCALE * cost,
+                                weighting the rollout by FACTOR
+  --gamma <F>                   discount factor, in (0, 1]
+  --selection <ucb1|ucb1-tuned|puct>
+                                tree policy [default: ucb1]
+  --exploration <C>             exploration constant of the tree policy [default: 2]
+  --widening <K> <ALPHA>        progressive widening, at most K * N^ALPHA children
+  --expansion-prob <P>          probability of expanding a non-root node
+  --exp-workers <N>             number of expansion workers
+  --sim-workers <N>             number of simulation workers
+  --unified-pool                let every worker run expansions and simulations
+  --root-parallel <N>           plan with N independent trees and merge their roots
+  --lp-extract                  extract with the LP extractor
+  --macro-actions               group the math rules into macro actions
+  --action-iters <N>            run every action for up to N egg iterations
+  --action-mask                 only consider rules that match somewhere
+  --root-cost <sum|shared-dag>  how the costs of several roots are combined
+  --reward-fn <absolute-delta|relative-improvement|log-ratio|terminal-only>
+  --cost-threshold <F>          stop once the cost drops below this
+  --iter-limit <N>              maximum rewrites applied to the e-graph
+  --node-limit <N>              node limit of each egg run
+  --time-limit <S>              time limit of each egg run, in seconds
+  --seed <N>                    seed of every random choice of the search
+  --reuse-tree                  keep the subtree of the chosen action across steps
+  --replay-checkpoints          checkpoint action histories instead of e-graphs
+  --max-snapshots <N>           keep at most N e-graph snapshots in the tree
+  --memory-limit <MB>           bound the memory of checkpoints and tasks
+  --deterministic               apply worker results in task order, so a seed
+                                reproduces the same result
+  --log-level <LEVEL>           off, error, warn, info (default), debug or trace
+  -h, --help                    print this message";
+
+/// Prints log records of the search to stderr.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+struct Cli {
+    lang: String,
+    macro_actions: bool,
+    action_iters: usize,
+    files: Vec<String>,
+    log_level: log::LevelFilter,
+    args: MCTSArgs,
+}
+
+fn parse_cli(mut argv: impl Iterator<Item = String>) -> Result<Cli, String> {
+    let mut selection = "ucb1".to_string();
+    let mut exploration = 2.0;
+    let mut widening = None;
+    let mut rollout = None;
+    let mut rollout_weights = None;
+    let mut epsilon = 0.1;
+    let mut cli = Cli {
+        lang: "math".to_string(),
+        macro_actions: false,
+        action_iters: 1,
+        files: vec![],
+        log_level: log::LevelFilter::Info,
+        args: MCTSArgs::default(),
+    };
+    while let Some(arg) = argv.next() {
+        let mut value = |flag: &str| argv.next().ok_or(format!("{} expects a value", flag));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--lang" => cli.lang = value(&arg)?,
+            "--budget" => cli.args.budget = parse(&arg, value(&arg)?)?,
+            "--planning-time-ms" => {
+                let ms = parse(&arg, value(&arg)?)?;
+                cli.args.planning_time_limit = Some(Duration::from_millis(ms));
+            }
+            "--max-sim-step" => cli.args.max_sim_step = parse(&arg, value(&arg)?)?,
+            "--task-timeout-ms" => {
+                let ms = parse(&arg, value(&arg)?)?;
+                cli.args.task_timeout = Some(Duration::from_millis(ms));
+            }
+            "--rollout" => rollout = Some(value(&arg)?),
+            "--epsilon" => epsilon = parse(&arg, value(&arg)?)?,
+            "--rollouts-per-leaf" => cli.args.rollouts_per_leaf = parse(&arg, value(&arg)?)?,
+            "--rollout-aggregation" => {
+                cli.args.rollout_aggregation = match value(&arg)?.as_str() {
+                    "mean" => RolloutAggregation::Mean,
+                    "max" => RolloutAggregation::Max,
+                    other => return Err(format!("unknown rollout aggregation {}", other)),
+                }
+            }
+            "--rollout-weights" => {
+                let weights: Vec<f32> = value(&arg)?
+                    .split(',')
+                    .map(|w| parse(&arg, w.to_string()))
+                    .collect::<Result<_, _>>()?;
+                rollout_weights = Some(weights);
+            }
+            "--value" => {
+                let scale = parse(&arg, value(&arg)?)?;
+                cli.args.value_factor = parse(&arg, value(&arg)?)?;
+                cli.args.value_estimator = Some(Arc::new(ProportionalValue { scale }));
+            }
+            "--gamma" => cli.args.gamma = parse(&arg, value(&arg)?)?,
+            "--selection" => selection = value(&arg)?,
+            "--exploration" => exploration = parse(&arg, value(&arg)?)?,
+            "--widening" => {
+                let k = parse(&arg, value(&arg)?)?;
+                widening = Some((k, parse(&arg, value(&arg)?)?));
+            }
+            "--expansion-prob" => cli.args.expansion_prob = parse(&arg, value(&arg)?)?,
+            "--exp-workers" => cli.args.expansion_worker_num = parse(&arg, value(&arg)?)?,
+            "--sim-workers" => cli.args.simulation_worker_num = parse(&arg, value(&arg)?)?,
+            "--unified-pool" => cli.args.unified_pool = true,
+            "--root-parallel" => cli.args.root_parallel = parse(&arg, value(&arg)?)?,
+            "--lp-extract" => cli.args.lp_extract = true,
+            "--macro-actions" => cli.macro_actions = true,
+            "--action-iters" => cli.action_iters = parse(&arg, value(&arg)?)?,
+            "--action-mask" => cli.args.action_mask = true,
+            "--root-cost" => {
+                cli.args.root_cost = match value(&arg)?.as_str() {
+                    "sum" => RootCost::Sum,
+                    "shared-dag" => RootCost::SharedDag,
+                    other => return Err(format!("unknown root cost {}", other)),
+                }
+            }
+            "--reward-fn" => {
+                cli.args.reward_fn = match value(&arg)?.as_str() {
+                    "absolute-delta" => RewardFn::AbsoluteDelta,
+                    "relative-improvement" => RewardFn::RelativeImprovement,
+                    "log-ratio" => RewardFn::LogRatio,
+                    "terminal-only" => RewardFn::TerminalOnly,
+                    other => return Err(format!("unknown reward fn {}", other)),
+                }
+            }
+            "--cost-threshold" => cli.args.cost_threshold = parse(&arg, value(&arg)?)?,
+            "--iter-limit" => cli.args.iter_limit = parse(&arg, value(&arg)?)?,
+            "--node-limit" => cli.args.node_limit = parse(&arg, value(&arg)?)?,
+            "--time-limit" => cli.args.time_limit = parse(&arg, value(&arg)?)?,
+            "--seed" => cli.args.seed = Some(parse(&arg, value(&arg)?)?),
+            "--deterministic" => cli.args.deterministic = true,
+            "--reuse-tree" => cli.args.reuse_tree = true,
+            "--replay-checkpoints" => cli.args.checkpoint_mode = CheckpointMode::Replay,
+            "--max-snapshots" => cli.args.max_snapshots = Some(parse(&arg, value(&arg)?)?),
+            "--memory-limit" => {
+                let mb: usize = parse(&arg, value(&arg)?)?;
+                cli.args.memory_limit = Some(mb * 1_000_000);
+            }
+            "--log-level" => cli.log_level = parse(&arg, value(&arg)?)?,
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => cli.files.push(arg),
+        }
+    }
+    if cli.files.is_empty() {
+        cli.files.push("-".to_string());
+    }
+    let rollout = rollout.unwrap_or_else(|| match rollout_weights {
+        Some(_) => "weighted".to_string(),
+        None => "uniform".to_string(),
+    });
+    let rollout_policy: Arc<dyn RolloutPolicy> = match (rollout.as_str(), rollout_weights) {
+        ("weighted", Some(weights)) => Arc::new(RuleWeighted { weights }),
+        ("weighted", None) => return Err("--rollout weighted needs --rollout-weights".to_string()),
+        (other, Some(_)) => {
+            return Err(format!(
+                "--rollout-weights conflicts with --rollout {}",
+                other
+            ))
+        }
+        ("uniform", None) => Arc::new(Uniform),
+        ("epsilon-greedy", None) => Arc::new(EpsilonGreedy { epsilon }),
+        ("skip-saturated", None) => Arc::new(SkipSaturated),
+        (other, None) => return Err(format!("unknown rollout policy {}", other)),
+    };
+    cli.args.rollout_policy = rollout_policy;
+    let c = exploration;
+    let policy: Arc<dyn SelectionPolicy> = match selection.as_str() {
+        "ucb1" => Arc::new(Ucb1 { c }),
+        "ucb1-tuned" => Arc::new(Ucb1Tuned { c }),
+        "puct" => Arc::new(Puct { c, priors: None }),
+        other => return Err(format!("unknown selection policy {}", other)),
+    };
+    cli.args.selection_policy = match widening {
+        Some((k, alpha)) => Arc::new(ProgressiveWidening { policy, k, alpha }),
+        None => policy,
+    };
+    Ok(cli)
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, flag))
+}
+
+fn read_inputs(files: &[String]) -> Result<Vec<String>, String> {
+    let mut inputs = vec![];
+    for file in files {
+        let reader: Box<dyn BufRead> = if file == "-" {
+            Box::new(BufReader::new(std::io::stdin()))
+        } else {
+            let f = std::fs::File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+            Box::new(BufReader::new(f))
+        };
+        for line in reader.lines() {
+            let line = line.map_err(|e| format!("{}: {}", file, e))?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with(';') {
+                inputs.push(line.to_string());
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+/// Optimise each input separately, printing the best expression and its cost.
+fn optimise<L, N, CF>(
+    inputs: &[String],
+    rules: Vec<Rewrite<L, N>>,
+    cf: CF,
+    args: &MCTSArgs,
+) -> Result<(), String>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync + std::fmt::Display,
+    <L as FromOp>::Error: std::fmt::Display,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    for input in inputs {
+        let expr: RecExpr<L> = input
+            .parse()
+            .map_err(|e| format!("cannot parse {}: {}", input, e))?;
+        let mut egraph = EGraph::<L, N>::default();
+        let root = egraph.add_expr(&expr);
+        egraph.rebuild();
+        let result = run::run_mcts(
+            egraph,
+            &[root],
+            rules.clone(),
+            cf.clone(),
+            Some(args.clone()),
+        )
+        .map_err(|e| format!("{}: {}", input, e))?;
+        println!(
+            "{} -> {} with cost {} -> {}",
+            expr, result.best_exprs[0], result.base_cost, result.final_cost
+        );
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = parse_cli(std::env::args().skip(1)).and_then(|mut cli| {
+        log::set_logger(&StderrLogger).map_err(|e| e.to_string())?;
+        log::set_max_level(cli.log_level);
+        let inputs = read_inputs(&cli.files)?;
+        let iters = cli.action_iters;
+        let actions = match (cli.lang.as_str(), cli.macro_actions) {
+            ("math", true) => Some(math::macro_actions()),
+            (_, true) => return Err(format!("no macro actions for {}", cli.lang)),
+            ("simple", false) => Some(Action::per_rule(&simple::rules())),
+            (_, false) => Some(Action::per_rule(&math::rules())),
+        };
+        cli.args.actions = actions.map(|actions| {
+            actions
+                .into_iter()
+                .map(|a| a.with_iter_limit(iters))
+                .collect()
+        });
+        match cli.lang.as_str() {
+            "simple" => optimise(&inputs, simple::rules(), simple::AstSize, &cli.args),
+            "math" => optimise(&inputs, math::rules(), math::MathCostFn, &cli.args),
+            other => Err(format!("unknown language {}", other)),
+        }
+    });
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::eg_env::Ckpt;
+
+use egg::{Analysis, EClass, EGraph, Id, Language};
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Approximate heap bytes of an e-graph: every e-node is held by its class, the
+/// hashcons and the parent lists, every e-class by the class map and union-find.
+pub fn egraph_bytes<L, N>(egraph: &EGraph<L, N>) -> usize
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    let node_bytes = 3 * size_of::<L>() + 2 * size_of::<Id>();
+    let class_bytes = size_of::<EClass<L, N::Data>>() + 2 * size_of::<Id>();
+    egraph.total_number_of_nodes() * node_bytes + egraph.number_of_classes() * class_bytes
+}
+
+/// Checkpoints of the expanded nodes, keyed by their saving index.
+///
+/// At most `max_snapshots` of them keep their e-graph snapshot; beyond that the
+/// least recently used snapshot is dropped, and its state is replayed from the
+/// action history when next restored. [`CkptStore::shrink_to`] evicts the same
+/// way down to a byte budget.
+pub struct CkptStore<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    max_snapshots: Option<usize>,
+    // checkpoint, the tick it was last used at and its snapshot bytes
+    ckpts: HashMap<u32, (Ckpt<L, N>, u64, usize)>,
+    snapshots: usize,
+    bytes: usize,
+    tick: u64,
+}
+
+impl<L, N> CkptStore<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    pub fn new(max_snapshots: Option<usize>) -> Self {
+        CkptStore {
+            max_snapshots,
+            ckpts: HashMap::new(),
+            snapshots: 0,
+            bytes: 0,
+            tick: 0,
+        }
+    }
+
+    pub fn insert(&mut self, idx: u32, ckpt: Ckpt<L, N>) {
+        self.tick += 1;
+        let bytes = ckpt.egraph.as_deref().map_or(0, egraph_bytes);
+        if ckpt.egraph.is_some() {
+            self.snapshots += 1;
+        }
+        self.bytes += bytes;
+        if let Some((old, _, old_bytes)) = self.ckpts.insert(idx, (ckpt, self.tick, bytes)) {
+            if old.egraph.is_some() {
+                self.snapshots -= 1;
+            }
+            self.bytes -= old_bytes;
+        }
+        if let Some(max_snapshots) = self.max_snapshots {
+            while self.snapshots > max_snapshots {
+                self.evict_lru();
+            }
+        }
+    }
+
+    /// Evict the least recently used snapshots until they take at most `max_bytes`.
+    pub fn shrink_to(&mut self, max_bytes: usize) {
+        while self.bytes > max_bytes && self.snapshots > 0 {
+            self.evict_lru();
+        }
+    }
+
+    /// The checkpoint at `idx`, marked as most recently used.
+    pub fn get(&mut self, idx: u32) -> Option<&Ckpt<L, N>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.ckpts.get_mut(&idx).map(|(ckpt, last_used, _)| {
+            *last_used = tick;
+            &*ckpt
+        })
+    }
+
+    pub fn retain<F: FnMut(u32) -> bool>(&mut self, mut keep: F) {
+        self.ckpts.retain(|idx, _| keep(*idx));
+        self.snapshots = self
+            .ckpts
+            .values()
+            .filter(|(ckpt, _, _)| ckpt.egraph.is_some())
+            .count();
+        self.bytes = self.ckpts.values().map(|(_, _, bytes)| bytes).sum();
+    }
+
+    pub fn remove(&mut self, idx: u32) {
+        if let Some((ckpt, _, bytes)) = self.ckpts.remove(&idx) {
+            if ckpt.egraph.is_some() {
+                self.snapshots -= 1;
+            }
+            self.bytes -= bytes;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.ckpts.clear();
+        self.snapshots = 0;
+        self.bytes = 0;
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.ckpts.len()
+    }
+
+    /// Number of checkpoints holding an e-graph snapshot.
+    #[allow(dead_code)]
+    pub fn snapshots(&self) -> usize {
+        self.snapshots
+    }
+
+    /// Approximate bytes of the snapshots held, see [`egraph_bytes`].
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn evict_lru(&mut self) {
+        let (ckpt, _, bytes) = self
+            .ckpts
+            .values_mut()
+            .filter(|(ckpt, _, _)| ckpt.egraph.is_some())
+            .min_by_key(|(_, last_used, _)| *last_used)
+            .unwrap();
+        ckpt.egraph = None;
+        self.snapshots -= 1;
+        self.bytes -= *bytes;
+        *bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use egg::{EGraph, SymbolLang};
+    use std::sync::Arc;
+
+    fn ckpt(cnt: u32) -> Ckpt<SymbolLang, ()> {
+        let mut egraph = EGraph::default();
+        egraph.add(SymbolLang::leaf("x"));
+        Ckpt {
+            cnt,
+            sat_counter: 0,
+            egraph: Some(Arc::new(egraph)),
+            history: vec![0; cnt as usize],
+            root_ids: vec![],
+            last_cost: 0.0,
+            saturated: vec![],
+            legal_actions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_snapshot() {
+        let mut store = CkptStore::new(Some(2));
+        store.insert(0, ckpt(0));
+        store.insert(1, ckpt(1));
+        store.get(0);
+        store.insert(2, ckpt(2));
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.snapshots(), 2);
+        assert!(store.get(0).unwrap().egraph.is_some());
+        assert!(store.get(1).unwrap().egraph.is_none());
+        assert_eq!(store.get(1).unwrap().history.len(), 1);
+        assert!(store.get(2).unwrap().egraph.is_some());
+
+        store.retain(|idx| idx != 0);
+        assert_eq!(store.snapshots(), 1);
+    }
+
+    #[test]
+    fn test_shrink_to_byte_budget() {
+        let mut store = CkptStore::new(None);
+        for idx in 0..4 {
+            store.insert(idx, ckpt(idx));
+        }
+        let bytes = store.bytes() / 4;
+        assert!(bytes > 0);
+        store.get(0);
+        store.shrink_to(2 * bytes);
+        assert_eq!(store.snapshots(), 2);
+        assert_eq!(store.bytes(), 2 * bytes);
+        assert!(store.get(0).unwrap().egraph.is_some());
+        assert!(store.get(1).unwrap().egraph.is_none());
+        store.shrink_to(0);
+        assert_eq!(store.bytes(), 0);
+    }
+
+    #[test]
+    fn test_unbounded() {
+        let mut store = CkptStore::new(None);
+        for idx in 0..10 {
+            store.insert(idx, ckpt(idx));
+        }
+        assert_eq!(store.snapshots(), 10);
+        store.clear();
+        assert_eq!(store.len(), 0);
+    }
+}
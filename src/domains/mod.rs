@@ -0,0 +1,3 @@
+//! Ready-made languages, rule sets and cost functions to benchmark the search on.
+pub mod math;
+pub mod simple;
@@ -1,14 +1,8 @@
-mod eg_env;
-mod env;
-mod node;
-mod pool_manager;
-mod run;
-mod tree;
-mod workers;
+//! A minimal arithmetic language, and a `Clone` AST-size cost; handy for smoke tests.
 use egg::*;
 
 define_language! {
-    enum SimpleLanguage {
+    pub enum SimpleLanguage {
         Num(i32),
         "+" = Add([Id; 2]),
         "*" = Mul([Id; 2]),
@@ -16,7 +10,7 @@ define_language! {
     }
 }
 
-fn make_rules() -> Vec<Rewrite<SimpleLanguage, ()>> {
+pub fn rules() -> Vec<Rewrite<SimpleLanguage, ()>> {
     vec![
         rewrite!("commute-add"; "(+ ?a ?b)" => "(+ ?b ?a)"),
         rewrite!("commute-mul"; "(* ?a ?b)" => "(* ?b ?a)"),
@@ -26,7 +20,7 @@ fn make_rules() -> Vec<Rewrite<SimpleLanguage, ()>> {
     ]
 }
 
-// from egg, and we need Clone trait
+/// egg's `AstSize`, made `Clone` so that every worker can own a copy.
 #[derive(Debug, Clone)]
 pub struct AstSize;
 impl<L: Language> CostFunction<L> for AstSize {
@@ -39,16 +33,8 @@ impl<L: Language> CostFunction<L> for AstSize {
     }
 }
 
-#[cfg_attr(docsrs, doc(cfg(feature = "lp")))]
 impl<L: Language, N: Analysis<L>> LpCostFunction<L, N> for AstSize {
     fn node_cost(&mut self, _egraph: &EGraph<L, N>, _eclass: Id, _enode: &L) -> f64 {
         1.0
     }
 }
-
-fn main() {
-    let expr = "(* 0 42)".parse().unwrap();
-    let runner = Runner::default().with_expr(&expr);
-    let root = runner.roots[0];
-    run::run_mcts(runner.egraph, root, make_rules(), AstSize, None);
-}
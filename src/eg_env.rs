@@ -1,112 +1,273 @@
+use crate::action::Action;
 use crate::env::Info;
+use crate::error::RmctsError;
+use crate::reward::{IntoReward, RewardFn, RootCost};
+use crate::run::{CheckpointMode, MCTSArgs};
 use egg::{
-    Analysis, CostFunction, EGraph, Extractor, Id, Language, LpCostFunction, LpExtractor, Rewrite,
-    Runner, SimpleScheduler, StopReason,
+    Analysis, CostFunction, EGraph, Extractor, Id, Language, LpCostFunction, LpExtractor, RecExpr,
+    Rewrite, Runner, SimpleScheduler, StopReason,
 };
-use std::time::Duration;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+/// A state of the env. The e-graph snapshot is immutable and shared, so cloning a
+/// checkpoint (e.g. into a task) is cheap; without a snapshot the state is rebuilt
+/// by replaying `history` from the initial e-graph.
 #[derive(Clone)]
 pub struct Ckpt<L, N>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
 {
     pub cnt: u32,
     pub sat_counter: usize,
-    pub egraph: EGraph<L, N>,
-    pub root_id: Id,
-    pub last_cost: usize,
+    pub egraph: Option<Arc<EGraph<L, N>>>,
+    pub history: Vec<usize>,
+    pub root_ids: Vec<Id>,
+    pub last_cost: f64,
+    pub saturated: Vec<bool>,
+    pub legal_actions: Vec<bool>,
 }
 
 pub struct EgraphEnv<L, N, CF>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
     init_egraph: EGraph<L, N>,
     pub egraph: EGraph<L, N>,
     cf: CF,
     lp_extract: bool,
-    root_id: Id,
-    num_rules: usize,
+    root_ids: Vec<Id>,
+    root_cost: RootCost,
     rules: Vec<Rewrite<L, N>>,
+    actions: Vec<Action>,
+    reward_fn: RewardFn,
+    action_mask: bool,
+    checkpoint_mode: CheckpointMode,
 
     node_limit: usize,
     time_limit: std::time::Duration,
 
-    pub base_cost: usize,
-    pub last_cost: usize,
+    pub base_cost: f64,
+    pub last_cost: f64,
     cnt: u32,
     sat_counter: usize,
+    // actions taken since reset
+    history: Vec<usize>,
+    // actions that saturated since the egraph last changed
+    saturated: Vec<bool>,
+    legal_actions: Vec<bool>,
+    // cooperative cancellation of the current task, checked between egg iterations
+    cancel: Option<Arc<AtomicBool>>,
+    deadline: Option<Instant>,
 }
 
 impl<L, N, CF> EgraphEnv<L, N, CF>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
-    pub fn new(
+    /// Build an env configured by the env-related fields of `args`.
+    pub fn from_args(
         egraph: EGraph<L, N>,
-        root_id: Id,
+        root_ids: Vec<Id>,
         rules: Vec<Rewrite<L, N>>,
         cf: CF,
-        lp_extract: bool,
-        node_limit: usize,
-        time_limit: usize,
-    ) -> Self {
-        assert!(!lp_extract); // TODO lp_extract only gets expr, but we need to compute cost
-                              // init expr cost; NOTE: expr length != expr cost
-        let (base_cost, _) = Extractor::new(&egraph, cf.clone()).find_best(root_id);
-        let base_cost = usize::try_from(base_cost).unwrap();
-        // let len = expr.as_ref().len();
-        // assert_eq!(len, base_cost);
-        //
-        EgraphEnv {
+        args: &MCTSArgs,
+    ) -> Result<Self, RmctsError> {
+        // init expr cost
+        let (base_cost, _) = extract(
+            &egraph,
+            &root_ids,
+            cf.clone(),
+            args.lp_extract,
+            args.root_cost,
+        )?;
+        let env = EgraphEnv {
             init_egraph: egraph,
             egraph: EGraph::default(),
-            cf: cf,
-            lp_extract: lp_extract,
-            root_id: root_id,
-            num_rules: rules.len(),
-            rules: rules,
-            node_limit: node_limit,
-            time_limit: Duration::from_secs(time_limit.try_into().unwrap()),
-
-            base_cost: base_cost,
-            last_cost: 0,
+            cf,
+            lp_extract: args.lp_extract,
+            root_ids,
+            root_cost: args.root_cost,
+            actions: Action::per_rule(&rules),
+            rules,
+            reward_fn: RewardFn::default(),
+            action_mask: false,
+            checkpoint_mode: CheckpointMode::Snapshot,
+            node_limit: args.node_limit,
+            time_limit: Duration::from_secs(args.time_limit.try_into().unwrap()),
+
+            base_cost,
+            last_cost: 0.0,
             cnt: 0,
             sat_counter: 0,
+            history: vec![],
+            saturated: vec![],
+            legal_actions: vec![],
+            cancel: None,
+            deadline: None,
+        };
+        let env = env
+            .with_reward_fn(args.reward_fn)
+            .with_action_mask(args.action_mask)
+            .with_checkpoint_mode(args.checkpoint_mode);
+        let env = match &args.actions {
+            Some(actions) => env.with_actions(actions.clone())?,
+            None => env,
+        };
+        args.selection_policy.validate(env.get_action_space())?;
+        Ok(env)
+    }
+
+    /// Replace the action space, one action per rule by default.
+    pub fn with_actions(mut self, actions: Vec<Action>) -> Result<Self, RmctsError> {
+        let invalid = |msg: String| Err(RmctsError::InvalidConfig(msg));
+        if actions.is_empty() {
+            return invalid("no actions given".to_string());
         }
+        for action in actions.iter() {
+            if action.rules.is_empty() {
+                return invalid(format!("action {} has no rules", action.name));
+            }
+            if action.iter_limit == 0 {
+                return invalid(format!("action {} has iter_limit 0", action.name));
+            }
+            if let Some(rule) = action.rules.iter().find(|&&rule| rule >= self.rules.len()) {
+                return invalid(format!("action {} has no rule {}", action.name, rule));
+            }
+        }
+        self.actions = actions;
+        Ok(self)
+    }
+
+    pub fn with_reward_fn(mut self, reward_fn: RewardFn) -> Self {
+        self.reward_fn = reward_fn;
+        self
+    }
+
+    /// Mask out actions without a match, see [`EgraphEnv::legal_actions`].
+    pub fn with_action_mask(mut self, action_mask: bool) -> Self {
+        self.action_mask = action_mask;
+        self
+    }
+
+    /// How [`EgraphEnv::checkpoint`] stores the e-graph.
+    pub fn with_checkpoint_mode(mut self, checkpoint_mode: CheckpointMode) -> Self {
+        self.checkpoint_mode = checkpoint_mode;
+        self
+    }
+
+    /// Stop every [`EgraphEnv::step`] between egg iterations once `cancel` is set or
+    /// `deadline` has passed, until cleared with `(None, None)`.
+    pub fn set_cancellation(&mut self, cancel: Option<Arc<AtomicBool>>, deadline: Option<Instant>) {
+        self.cancel = cancel;
+        self.deadline = deadline;
+    }
+
+    /// Whether the current task was cancelled or ran past its deadline.
+    pub fn cancelled(&self) -> bool {
+        is_cancelled(&self.cancel, self.deadline)
     }
 
     pub fn reset(&mut self) {
         self.cnt = 0;
         self.sat_counter = 0;
+        self.history.clear();
         self.egraph = self.init_egraph.clone();
+        self.egraph.rebuild();
         self.last_cost = self.base_cost;
+        self.saturated = vec![false; self.actions.len()];
+        self.update_legal_actions();
     }
 
-    pub fn step(&mut self, action: usize) -> ((), f32, bool, Info) {
+    /// Actions that can still change the e-graph: with the action mask, those with a
+    /// rule whose searcher matches somewhere and that did not saturate since the
+    /// e-graph last changed (a rule whose matches are all present already saturates
+    /// once applied); without it, every action.
+    pub fn legal_actions(&self) -> &[bool] {
+        &self.legal_actions
+    }
+
+    /// Actions that saturated since the e-graph last changed.
+    pub fn saturated(&self) -> &[bool] {
+        &self.saturated
+    }
+
+    fn update_legal_actions(&mut self) {
+        self.legal_actions = if self.action_mask {
+            self.actions
+                .iter()
+                .zip(self.saturated.iter())
+                .map(|(action, saturated)| {
+                    !saturated
+                        && action
+                            .rules
+                            .iter()
+                            .any(|&rule| !self.rules[rule].search(&self.egraph).is_empty())
+                })
+                .collect()
+        } else {
+            vec![true; self.actions.len()]
+        };
+    }
+
+    pub fn step(&mut self, action: usize) -> Result<((), f32, bool, Info), RmctsError> {
         // run egg
         let egraph = std::mem::take(&mut self.egraph);
-        let rule = vec![self.rules[action].clone()];
+        let macro_action = &self.actions[action];
+        let rules: Vec<Rewrite<L, N>> = macro_action
+            .rules
+            .iter()
+            .map(|&rule| self.rules[rule].clone())
+            .collect();
         let runner: Runner<L, N> = Runner::default()
             .with_egraph(egraph)
-            .with_iter_limit(1)
+            .with_iter_limit(macro_action.iter_limit)
             .with_node_limit(self.node_limit)
             .with_time_limit(self.time_limit)
-            .with_scheduler(SimpleScheduler)
-            .run(&rule);
+            .with_scheduler(SimpleScheduler);
+        let runner = match (self.cancel.clone(), self.deadline) {
+            (None, None) => runner,
+            (cancel, deadline) => runner.with_hook(move |_| {
+                if is_cancelled(&cancel, deadline) {
+                    Err("cancelled".to_string())
+                } else {
+                    Ok(())
+                }
+            }),
+        };
+        let runner = runner.run(&rules);
         let report = runner.report();
 
         // reclaim the partial egraph
@@ -119,16 +280,17 @@ where
         //     .sum();
 
         // run extract
-        let best_cost = if self.lp_extract {
-            let expr = LpExtractor::new(&self.egraph, self.cf.clone()).solve(self.root_id);
-            expr.as_ref().len()
-        } else {
-            let (cost, _) = Extractor::new(&self.egraph, self.cf.clone()).find_best(self.root_id);
-            usize::try_from(cost).unwrap()
-        };
+        let (best_cost, _) = extract(
+            &self.egraph,
+            &self.root_ids,
+            self.cf.clone(),
+            self.lp_extract,
+            self.root_cost,
+        )?;
 
         // compute transition
         self.cnt += 1;
+        self.history.push(action);
         let mut done = false;
         match runner.stop_reason.as_ref().unwrap() {
             StopReason::NodeLimit(_) => {
@@ -138,7 +300,7 @@ where
             StopReason::TimeLimit(time) => {
                 // TODO this indicates egraph is exploded?
                 done = true;
-                println!(
+                warn!(
                     "EGG TimeLimit {}s - {}s - {} - {} - {}",
                     time,
                     report.total_time,
@@ -150,22 +312,40 @@ where
             StopReason::Saturated => {
                 // TODO sat_counter is enough to indicate saturation?
                 self.sat_counter += 1;
-                if self.sat_counter == (self.num_rules) {
+                if self.sat_counter == self.actions.len() {
                     done = true;
                 }
             }
             StopReason::IterationLimit(_) => self.sat_counter = 0,
             _ => self.sat_counter = 0,
         }
+        if matches!(runner.stop_reason, Some(StopReason::Saturated)) {
+            self.saturated[action] = true;
+        } else {
+            self.saturated.iter_mut().for_each(|s| *s = false);
+        }
+        self.update_legal_actions();
+        if !self.legal_actions.contains(&true) {
+            // no rule can change the egraph any more
+            done = true;
+        }
         // compute reward
-        let reward = std::cmp::max(self.last_cost - best_cost, 0);
+        let reward = self
+            .reward_fn
+            .step_reward(self.base_cost, self.last_cost, best_cost, done);
         self.last_cost = best_cost;
         let info = Info {
             report: report,
             best_cost: best_cost,
         };
 
-        ((), (reward as f32), done, info)
+        Ok(((), reward, done, info))
+    }
+
+    /// Reward owed when an episode is truncated in the current state.
+    pub fn truncation_reward(&self) -> f32 {
+        self.reward_fn
+            .truncation_reward(self.base_cost, self.last_cost)
     }
 
     // immediately extract and get reward
@@ -178,24 +358,259 @@ where
     // }
 
     pub fn get_action_space(&self) -> usize {
-        self.num_rules
+        self.actions.len()
     }
 
+    pub fn get_action_name(&self, action: usize) -> String {
+        self.actions[action].name.clone()
+    }
+
+    /// The best expression of each root, in the order of the roots.
+    pub fn get_best_exprs(&self) -> Result<Vec<RecExpr<L>>, RmctsError> {
+        let (_, exprs) = extract(
+            &self.egraph,
+            &self.root_ids,
+            self.cf.clone(),
+            self.lp_extract,
+            self.root_cost,
+        )?;
+        Ok(exprs)
+    }
+
+    /// Checkpoint the current state; in [`CheckpointMode::Replay`] only the action
+    /// history is kept.
     pub fn checkpoint(&self) -> Ckpt<L, N> {
+        match self.checkpoint_mode {
+            CheckpointMode::Snapshot => self.snapshot(),
+            CheckpointMode::Replay => self.checkpoint_with(None),
+        }
+    }
+
+    /// Checkpoint the current state with an e-graph snapshot, whatever the mode.
+    pub fn snapshot(&self) -> Ckpt<L, N> {
+        self.checkpoint_with(Some(Arc::new(self.egraph.clone())))
+    }
+
+    fn checkpoint_with(&self, egraph: Option<Arc<EGraph<L, N>>>) -> Ckpt<L, N> {
         Ckpt {
             cnt: self.cnt,
             sat_counter: self.sat_counter,
-            egraph: self.egraph.clone(),
-            root_id: self.root_id.clone(),
+            egraph,
+            history: self.history.clone(),
+            root_ids: self.root_ids.clone(),
             last_cost: self.last_cost,
+            saturated: self.saturated.clone(),
+            legal_actions: self.legal_actions.clone(),
         }
     }
 
-    pub fn restore(&mut self, checkpoint_data: Ckpt<L, N>) {
+    /// Restore a checkpoint, replaying its history from the initial e-graph if it
+    /// has no snapshot. Replay reproduces the state as long as egg hits no time
+    /// limit along the way.
+    pub fn restore(&mut self, checkpoint_data: Ckpt<L, N>) -> Result<(), RmctsError> {
+        match checkpoint_data.egraph {
+            Some(egraph) => self.egraph = (*egraph).clone(),
+            None => {
+                self.reset();
+                for &action in checkpoint_data.history.iter() {
+                    self.step(action)?;
+                }
+            }
+        }
         self.cnt = checkpoint_data.cnt;
         self.sat_counter = checkpoint_data.sat_counter;
-        self.egraph = checkpoint_data.egraph;
-        self.root_id = checkpoint_data.root_id;
+        self.history = checkpoint_data.history;
+        self.root_ids = checkpoint_data.root_ids;
         self.last_cost = checkpoint_data.last_cost;
+        self.saturated = checkpoint_data.saturated;
+        self.legal_actions = checkpoint_data.legal_actions;
+        Ok(())
+    }
+}
+
+fn is_cancelled(cancel: &Option<Arc<AtomicBool>>, deadline: Option<Instant>) -> bool {
+    cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Extract the best expression of every root and their joint cost.
+///
+/// Costs are brought to the reward scale with [`IntoReward`] and combined according
+/// to `root_cost`. A DAG cost is measured with the [`LpCostFunction`], i.e. shared
+/// sub-expressions are paid for once; with `lp_extract` every cost is a DAG cost.
+///
+/// Both extractors panic on malformed e-graphs (e.g. a root without any finite cost
+/// or a failing LP solver); the panic is turned into [`RmctsError::Extraction`].
+fn extract<L, N, CF>(
+    egraph: &EGraph<L, N>,
+    root_ids: &[Id],
+    mut cf: CF,
+    lp_extract: bool,
+    root_cost: RootCost,
+) -> Result<(f64, Vec<RecExpr<L>>), RmctsError>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    let (tree_cost, exprs) = panic::catch_unwind(AssertUnwindSafe(|| {
+        if lp_extract {
+            let exprs = match root_cost {
+                RootCost::SharedDag => {
+                    let (expr, ids) = LpExtractor::new(egraph, cf.clone()).solve_multiple(root_ids);
+                    ids.iter().map(|&id| subexpr(&expr, id)).collect()
+                }
+                RootCost::Sum => root_ids
+                    .iter()
+                    .map(|&id| LpExtractor::new(egraph, cf.clone()).solve(id))
+                    .collect(),
+            };
+            (None, exprs)
+        } else {
+            let extractor = Extractor::new(egraph, cf.clone());
+            let mut tree_cost = 0.0;
+            let mut exprs = Vec::with_capacity(root_ids.len());
+            for &id in root_ids {
+                let (cost, expr) = extractor.find_best(id);
+                tree_cost += cost.into_reward();
+                exprs.push(expr);
+            }
+            (Some(tree_cost), exprs)
+        }
+    }))
+    .map_err(|e| {
+        let msg = if let Some(msg) = e.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = e.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            format!("roots {:?}", root_ids)
+        };
+        RmctsError::Extraction(msg)
+    })?;
+
+    let cost = match (tree_cost, root_cost) {
+        (Some(tree_cost), RootCost::Sum) => tree_cost,
+        (_, RootCost::SharedDag) => dag_cost(egraph, &exprs, &mut cf)?,
+        (None, RootCost::Sum) => {
+            let mut cost = 0.0;
+            for expr in exprs.iter() {
+                cost += dag_cost(egraph, std::slice::from_ref(expr), &mut cf)?;
+            }
+            cost
+        }
+    };
+    Ok((cost, exprs))
+}
+
+/// Sum of [`LpCostFunction::node_cost`] over the e-classes the nodes of `exprs`
+/// were extracted from, each e-class paid for once.
+fn dag_cost<L, N, CF>(
+    egraph: &EGraph<L, N>,
+    exprs: &[RecExpr<L>],
+    cf: &mut CF,
+) -> Result<f64, RmctsError>
+where
+    L: Language,
+    N: Analysis<L>,
+    CF: LpCostFunction<L, N>,
+{
+    let mut seen: HashSet<Id> = HashSet::new();
+    let mut cost = 0.0;
+    for expr in exprs {
+        let mut eclasses: Vec<Id> = Vec::with_capacity(expr.as_ref().len());
+        for node in expr.as_ref() {
+            let enode = node.clone().map_children(|i| eclasses[usize::from(i)]);
+            let eclass = egraph.lookup(enode.clone()).ok_or_else(|| {
+                RmctsError::Extraction("extracted node is not in the e-graph".to_string())
+            })?;
+            if seen.insert(eclass) {
+                cost += cf.node_cost(egraph, eclass, &enode);
+            }
+            eclasses.push(eclass);
+        }
+    }
+    Ok(cost)
+}
+
+/// The sub-expression of `expr` rooted at node `root`.
+fn subexpr<L: Language>(expr: &RecExpr<L>, root: Id) -> RecExpr<L> {
+    let nodes = expr.as_ref();
+    let root = usize::from(root);
+    // children always precede their parents in a RecExpr
+    let mut reachable = vec![false; root + 1];
+    reachable[root] = true;
+    for i in (0..=root).rev() {
+        if reachable[i] {
+            nodes[i].for_each(|child| reachable[usize::from(child)] = true);
+        }
+    }
+    let mut new_ids: HashMap<Id, Id> = HashMap::new();
+    let mut sub = RecExpr::default();
+    for i in (0..=root).filter(|&i| reachable[i]) {
+        let node = nodes[i].clone().map_children(|child| new_ids[&child]);
+        new_ids.insert(Id::from(i), sub.add(node));
+    }
+    sub
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use egg::SymbolLang;
+
+    // leaves cost 1, operators 2 per child
+    struct OpCost;
+    impl LpCostFunction<SymbolLang, ()> for OpCost {
+        fn node_cost(&mut self, _: &EGraph<SymbolLang, ()>, _: Id, enode: &SymbolLang) -> f64 {
+            1.0 + 2.0 * enode.len() as f64
+        }
+    }
+
+    #[test]
+    fn test_dag_cost_counts_shared_classes_once() {
+        let mut egraph: EGraph<SymbolLang, ()> = EGraph::default();
+        let exprs: Vec<RecExpr<SymbolLang>> = ["(+ (* x y) (* x y))", "(- (* x y) z)"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        for expr in exprs.iter() {
+            egraph.add_expr(expr);
+        }
+        egraph.rebuild();
+        // x, y, (* x y) and the sum; the tree cost would be 19
+        assert_eq!(dag_cost(&egraph, &exprs[..1], &mut OpCost).unwrap(), 12.0);
+        // z and the difference come on top of the shared product
+        assert_eq!(dag_cost(&egraph, &exprs, &mut OpCost).unwrap(), 18.0);
+
+        let missing: RecExpr<SymbolLang> = "(* x z)".parse().unwrap();
+        assert!(matches!(
+            dag_cost(&egraph, &[missing], &mut OpCost),
+            Err(RmctsError::Extraction(_))
+        ));
+    }
+
+    #[test]
+    fn test_subexpr() {
+        let expr: RecExpr<SymbolLang> = "(+ (* x y) (- z x))".parse().unwrap();
+        let product = expr
+            .as_ref()
+            .iter()
+            .position(|node| node.op.as_str() == "*")
+            .unwrap();
+        let sub = subexpr(&expr, Id::from(product));
+        assert_eq!(sub.to_string(), "(* x y)");
+        let root = Id::from(expr.as_ref().len() - 1);
+        assert_eq!(subexpr(&expr, root), expr);
     }
 }
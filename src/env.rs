@@ -7,7 +7,7 @@ use std::time::Duration;
 
 pub struct Info {
     pub report: Report,
-    pub best_cost: usize,
+    pub best_cost: f64,
 }
 
 #[allow(dead_code)]
@@ -122,7 +122,7 @@ where
         self.last_cost = best_cost;
         let info = Info {
             report: report,
-            best_cost: best_cost,
+            best_cost: best_cost as f64,
         };
 
         ((), (reward as f32), done, info)
@@ -0,0 +1,33 @@
+use std::fmt;
+
+/// Errors surfaced by rmcts instead of panicking deep inside the planner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RmctsError {
+    /// The search was configured with a nonsensical combination of arguments.
+    InvalidConfig(String),
+    /// A worker thread panicked, exited early or reported a failure.
+    WorkerCrashed(String),
+    /// No child of the node can be selected, e.g. none was expanded. At the root,
+    /// [`crate::run::run_mcts`] stops with `Done(Saturated)` instead.
+    NoLegalAction,
+    /// An action was expanded twice from the same node.
+    DuplicateChild(usize),
+    /// Extracting the best expression from the e-graph failed.
+    Extraction(String),
+}
+
+impl fmt::Display for RmctsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RmctsError::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            RmctsError::WorkerCrashed(msg) => write!(f, "worker crashed: {}", msg),
+            RmctsError::NoLegalAction => write!(f, "no legal action to select"),
+            RmctsError::DuplicateChild(action) => {
+                write!(f, "action {} was already expanded", action)
+            }
+            RmctsError::Extraction(msg) => write!(f, "extraction failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RmctsError {}
@@ -1,7 +1,18 @@
+pub mod action;
+mod ckpt_store;
+pub mod domains;
 mod eg_env;
 mod env;
+pub mod error;
 mod node;
+pub mod policy;
 mod pool_manager;
+pub mod reward;
+pub mod rollout;
+mod root_parallel;
 pub mod run;
+pub mod runner;
+#[cfg(test)]
+mod test_util;
 mod tree;
 mod workers;
@@ -1,3 +1,6 @@
+use crate::error::RmctsError;
+use crate::policy::{ChildStats, SelectionPolicy};
+use log::debug;
 use rand::Rng;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -19,7 +22,11 @@ pub struct Node {
     children_complete_visit_count: Vec<u32>,
     children_saturated: Vec<bool>,
     children_saturated_cnt: usize,
+    children_expanding: Vec<bool>,
+    legal_actions: Vec<bool>,
+    legal_action_cnt: usize,
     q_value: Vec<f32>,
+    q_sq_value: Vec<f32>,
 
     // self
     visit_count: u32,
@@ -49,7 +56,11 @@ impl Node {
             children_complete_visit_count: vec![0; action_n],
             children_saturated: vec![false; action_n],
             children_saturated_cnt: 0,
+            children_expanding: vec![false; action_n],
+            legal_actions: vec![true; action_n],
+            legal_action_cnt: action_n,
             q_value: vec![0.0; action_n],
+            q_sq_value: vec![0.0; action_n],
             visit_count: 0,
             traverse_history: HashMap::new(),
             visited_node_count: 0,
@@ -71,7 +82,11 @@ impl Node {
             children_complete_visit_count: vec![0; 1],
             children_saturated: vec![false; 1],
             children_saturated_cnt: 0,
+            children_expanding: vec![false; 1],
+            legal_actions: vec![true; 1],
+            legal_action_cnt: 1,
             q_value: vec![0.0; 1],
+            q_sq_value: vec![0.0; 1],
             visit_count: 0,
             traverse_history: HashMap::new(),
             visited_node_count: 0,
@@ -79,8 +94,21 @@ impl Node {
         }))
     }
 
+    /// Visits of this node, counted when a simulation through it starts.
+    #[allow(dead_code)]
+    pub fn visit_count(&self) -> u32 {
+        self.visit_count
+    }
+
     pub fn all_child_visited(&self) -> bool {
-        self.visited_node_count == self.action_n
+        self.visited_node_count >= self.legal_action_cnt
+    }
+
+    /// Restrict expansion to the actions that can change the state, see
+    /// `EgraphEnv::legal_actions`.
+    pub fn set_legal_actions(&mut self, legal_actions: Vec<bool>) {
+        self.legal_action_cnt = legal_actions.iter().filter(|&&legal| legal).count();
+        self.legal_actions = legal_actions;
     }
 
     pub fn no_child_available(&self) -> bool {
@@ -94,98 +122,140 @@ impl Node {
     pub fn shallow_clone(&self) -> NodeStub {
         NodeStub {
             action_n: self.action_n,
-            is_head: self.is_head,
             // NOTE: only children_visit_count is up-to-date with all selected action!
             children_visit_count: self.children_visit_count.clone(),
+            // an action is taken if its child exists or another task is expanding it;
+            // illegal actions are never taken
+            children_taken: self
+                .children
+                .iter()
+                .zip(self.children_expanding.iter())
+                .zip(self.legal_actions.iter())
+                .map(|((child, expanding), legal)| child.is_some() || *expanding || !legal)
+                .collect(),
         }
     }
 
-    pub fn select_uct_action(&self, max: bool) -> usize {
+    /// Reserve `action` for an in-flight expansion until its child is added.
+    pub fn mark_expanding(&mut self, action: usize) {
+        self.children_expanding[action] = true;
+    }
+
+    /// Release the reservation of an expansion that was abandoned.
+    pub fn unmark_expanding(&mut self, action: usize) {
+        self.children_expanding[action] = false;
+    }
+
+    /// Whether an in-flight task will still add a child to this node.
+    pub fn is_expanding(&self) -> bool {
+        self.children_expanding.contains(&true)
+    }
+
+    /// Whether `policy` lets this node expand another child.
+    pub fn can_widen(&self, policy: &dyn SelectionPolicy) -> bool {
+        let expanded = self
+            .children
+            .iter()
+            .zip(self.children_expanding.iter())
+            .filter(|(child, expanding)| child.is_some() || **expanding)
+            .count();
+        policy.can_widen(self.visit_count, expanded)
+    }
+
+    /// Statistics of every selectable child, i.e. expanded and not saturated,
+    /// indexed by action. If every child is saturated, the final pick falls back
+    /// to the best of them.
+    pub fn children_stats(&self) -> Vec<Option<ChildStats>> {
+        let skip_saturated = !self.no_child_available();
+        (0..self.action_n)
+            .map(|action| {
+                if self.children[action].is_none()
+                    || (self.children_saturated[action] && skip_saturated)
+                {
+                    return None;
+                }
+                Some(ChildStats {
+                    action,
+                    action_n: self.action_n,
+                    visit_count: self.children_visit_count[action],
+                    complete_visit_count: self.children_complete_visit_count[action],
+                    q_sum: self.q_value[action],
+                    q_sq_sum: self.q_sq_value[action],
+                })
+            })
+            .collect()
+    }
+
+    /// Select a child by `policy`, or by its mean return alone if `max`.
+    pub fn select_uct_action(
+        &self,
+        max: bool,
+        policy: &dyn SelectionPolicy,
+    ) -> Result<usize, RmctsError> {
         let mut best_score = std::f32::MIN;
         let mut best_action = std::usize::MAX;
-        let mut sat_count = 0;
-        let mut child_missing_count = 0;
-        for action in 0..(self.action_n as usize) {
-            if self.children[action].is_none() {
-                child_missing_count += 1;
-                continue;
-            }
-            if self.children_saturated[action] {
-                sat_count += 1;
-                continue;
-            }
-
-            let exploit_score =
-                self.q_value[action] / (self.children_complete_visit_count[action] as f32);
-            // let explore_score = f32::sqrt(
-            //     2.0 * f32::ln(self.visit_count as f32) / (self.children_visit_count[action] as f32),
-            // );
-            // TODO consider std?
-            let explore_score = if max {
-                0.0
+        for stats in self.children_stats().into_iter().flatten() {
+            let score = if max {
+                stats.mean()
             } else {
-                f32::sqrt(
-                    2.0 * f32::ln(self.visit_count as f32)
-                        / (self.children_visit_count[action] as f32),
-                )
+                policy.score(self.visit_count, &stats)
             };
-            let score = exploit_score + 2.0 * explore_score;
 
             if score > best_score {
                 best_score = score;
-                best_action = action;
+                best_action = stats.action;
             }
         }
         if best_action == std::usize::MAX {
-            panic!(
-                "{} - {} - {} - {}",
-                self.is_head, self.updated_node_count, sat_count, child_missing_count
-            );
+            return Err(RmctsError::NoLegalAction);
         }
         if max {
-            println!(
+            debug!(
                 "best_action {} and expected score {}",
                 best_action, best_score
             );
         }
-        best_action
+        Ok(best_action)
     }
 
     pub fn update_history(&mut self, idx: u32, action_taken: usize, reward: f32) {
         self.traverse_history.insert(idx, (action_taken, reward));
     }
 
+    /// Drop the history of task `idx`, which will never complete.
+    pub fn forget_history(&mut self, idx: u32) {
+        self.traverse_history.remove(&idx);
+    }
+
+    /// Tasks that went through this node and have not completed yet.
+    #[allow(dead_code)]
+    pub fn pending_history(&self) -> usize {
+        self.traverse_history.len()
+    }
+
     pub fn add_child(
         &mut self,
         expand_action: usize,
         saving_idx: u32,
         gamma: f32,
         child_saturated: bool,
+        legal_actions: Option<Vec<bool>>,
         self_node: Rc<RefCell<Node>>,
-    ) {
+    ) -> Result<(), RmctsError> {
+        if self.children[expand_action].is_some() {
+            return Err(RmctsError::DuplicateChild(expand_action));
+        }
+        self.children_expanding[expand_action] = false;
         if child_saturated {
             self.children_saturated[expand_action] = true;
             self.children_saturated_cnt += 1;
         }
-
-        match &self.children[expand_action] {
-            None => {
-                self.children[expand_action] = Some(Node::new(
-                    self.action_n,
-                    saving_idx,
-                    gamma,
-                    false,
-                    Some(self_node),
-                ))
-            }
-            Some(child) => panic!(
-                "self {} - action {} - to-add {} - existing child {}",
-                self.checkpoint_idx,
-                expand_action,
-                saving_idx,
-                child.borrow().checkpoint_idx
-            ),
+        let child = Node::new(self.action_n, saving_idx, gamma, false, Some(self_node));
+        if let Some(legal_actions) = legal_actions {
+            child.borrow_mut().set_legal_actions(legal_actions);
         }
+        self.children[expand_action] = Some(child);
+        Ok(())
     }
 
     pub fn update_incomplete(&mut self, idx: u32) {
@@ -199,7 +269,8 @@ impl Node {
 
     pub fn update_complete(&mut self, idx: u32, accu_reward: f32) -> f32 {
         let (action_taken, reward);
-        match self.traverse_history.get(&idx) {
+        // a task completes once, so its history is no longer needed
+        match self.traverse_history.remove(&idx) {
             Some((a, r)) => {
                 action_taken = a.clone();
                 reward = r.clone();
@@ -212,6 +283,7 @@ impl Node {
         }
         self.children_complete_visit_count[action_taken] += 1;
         self.q_value[action_taken] += this_accu_reward;
+        self.q_sq_value[action_taken] += this_accu_reward * this_accu_reward;
         this_accu_reward
     }
 }
@@ -227,32 +299,95 @@ impl Node {
 #[derive(Debug, Clone)]
 pub struct NodeStub {
     pub action_n: usize,
-    pub is_head: bool,
     pub children_visit_count: Vec<u32>,
+    pub children_taken: Vec<bool>,
 }
 
 impl NodeStub {
-    pub fn select_expansion_action(&self) -> usize {
-        let mut cnt = 0;
+    /// Pick a random action that is neither expanded nor being expanded, preferring
+    /// actions that have never been visited. `None` if every action is taken.
+    pub fn select_expansion_action<R: Rng>(&self, rng: &mut R) -> Option<usize> {
+        let free: Vec<usize> = (0..self.action_n)
+            .filter(|&action| !self.children_taken[action])
+            .collect();
+        let unvisited: Vec<usize> = free
+            .iter()
+            .copied()
+            .filter(|&action| self.children_visit_count[action] == 0)
+            .collect();
+        let candidates = if unvisited.is_empty() {
+            free
+        } else {
+            unvisited
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[rng.gen_range(0..candidates.len())])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expansion_skips_reserved_actions() {
+        let node = Node::new(3, 0, 1.0, true, None);
         let mut rng = rand::thread_rng();
-        let mut action: usize = 0;
-        loop {
-            if cnt < 20 {
-                action = rng.gen_range(0..self.action_n);
-            }
+        node.borrow_mut().mark_expanding(0);
+        node.borrow_mut().mark_expanding(2);
+        for _ in 0..20 {
+            let stub = node.borrow().shallow_clone();
+            assert_eq!(stub.select_expansion_action(&mut rng), Some(1));
+        }
+        node.borrow_mut().mark_expanding(1);
+        let stub = node.borrow().shallow_clone();
+        assert_eq!(stub.select_expansion_action(&mut rng), None);
+    }
 
-            if cnt > 100 {
-                return action;
-            }
+    #[test]
+    fn test_expansion_skips_illegal_actions() {
+        let node = Node::new(3, 0, 1.0, true, None);
+        let mut rng = rand::thread_rng();
+        node.borrow_mut()
+            .set_legal_actions(vec![false, true, false]);
+        for _ in 0..20 {
+            let stub = node.borrow().shallow_clone();
+            assert_eq!(stub.select_expansion_action(&mut rng), Some(1));
+        }
+        assert!(!node.borrow().all_child_visited());
+    }
 
-            if self.children_visit_count[action] == 0 {
-                return action;
-            }
+    #[test]
+    fn test_add_child_twice_is_an_error() {
+        let node = Node::new(2, 0, 1.0, true, None);
+        let parent = Rc::clone(&node);
+        assert!(node
+            .borrow_mut()
+            .add_child(1, 1, 1.0, false, None, Rc::clone(&parent))
+            .is_ok());
+        assert_eq!(
+            node.borrow_mut().add_child(1, 2, 1.0, false, None, parent),
+            Err(RmctsError::DuplicateChild(1))
+        );
+    }
 
-            if self.children_visit_count[action] > 0 && cnt < 10 {
-                cnt += 1;
-                continue;
-            }
+    #[test]
+    fn test_selection_falls_back_to_saturated_children() {
+        let node = Node::new(2, 0, 1.0, true, None);
+        let parent = Rc::clone(&node);
+        let policy = crate::policy::Ucb1 { c: 2.0 };
+        for (idx, action) in [(1, 0), (2, 1)] {
+            let mut node = node.borrow_mut();
+            node.add_child(action, idx, 1.0, true, None, Rc::clone(&parent))
+                .unwrap();
+            node.update_history(idx, action, action as f32);
+            node.update_incomplete(idx);
+            node.update_complete(idx, 0.0);
         }
+        assert!(node.borrow().no_child_available());
+        assert_eq!(node.borrow().select_uct_action(true, &policy), Ok(1));
+        assert!(node.borrow().select_uct_action(false, &policy).is_ok());
     }
 }
@@ -0,0 +1,232 @@
+use crate::error::RmctsError;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Statistics of one child of a node, as seen by a [`SelectionPolicy`].
+#[derive(Debug, Clone)]
+pub struct ChildStats {
+    /// The action leading to this child.
+    pub action: usize,
+    /// Number of actions of the parent.
+    pub action_n: usize,
+    /// Visits of the child, including in-flight (incomplete) ones.
+    pub visit_count: u32,
+    /// Visits of the child whose simulation has completed.
+    pub complete_visit_count: u32,
+    /// Sum of the returns of completed visits.
+    pub q_sum: f32,
+    /// Sum of the squared returns of completed visits.
+    pub q_sq_sum: f32,
+}
+
+impl ChildStats {
+    /// Mean return of the completed visits.
+    pub fn mean(&self) -> f32 {
+        self.q_sum / (self.complete_visit_count as f32)
+    }
+
+    /// Variance of the returns of the completed visits.
+    pub fn variance(&self) -> f32 {
+        let mean = self.mean();
+        (self.q_sq_sum / (self.complete_visit_count as f32) - mean * mean).max(0.0)
+    }
+
+    /// Pool the visits of the same child in another tree.
+    pub fn merge(&mut self, other: &ChildStats) {
+        self.visit_count += other.visit_count;
+        self.complete_visit_count += other.complete_visit_count;
+        self.q_sum += other.q_sum;
+        self.q_sq_sum += other.q_sq_sum;
+    }
+}
+
+/// Tree policy of the search: scores the expanded children of a node and decides
+/// whether the node may expand another one.
+///
+/// Visit counts include in-flight simulations, as in WU-UCT, so concurrent
+/// selections spread over different children.
+pub trait SelectionPolicy: Debug + Send + Sync {
+    /// Score of `child` under a parent visited `parent_visits` times; the child
+    /// with the highest score is selected.
+    fn score(&self, parent_visits: u32, child: &ChildStats) -> f32;
+
+    /// Whether a node visited `visit_count` times, with `expanded` children expanded
+    /// or being expanded, may expand another child.
+    fn can_widen(&self, _visit_count: u32, _expanded: usize) -> bool {
+        true
+    }
+
+    /// Reject a policy that does not fit a search over `action_n` actions.
+    fn validate(&self, _action_n: usize) -> Result<(), RmctsError> {
+        Ok(())
+    }
+}
+
+/// UCB1: `mean + c * sqrt(2 ln N / n)`.
+#[derive(Debug, Clone)]
+pub struct Ucb1 {
+    pub c: f32,
+}
+
+impl SelectionPolicy for Ucb1 {
+    fn score(&self, parent_visits: u32, child: &ChildStats) -> f32 {
+        let explore = f32::sqrt(2.0 * f32::ln(parent_visits as f32) / (child.visit_count as f32));
+        child.mean() + self.c * explore
+    }
+}
+
+/// UCB1-Tuned: UCB1 with the exploration term bounded by an upper confidence
+/// bound of the variance of the child's returns.
+#[derive(Debug, Clone)]
+pub struct Ucb1Tuned {
+    pub c: f32,
+}
+
+impl SelectionPolicy for Ucb1Tuned {
+    fn score(&self, parent_visits: u32, child: &ChildStats) -> f32 {
+        let log_ratio = f32::ln(parent_visits as f32) / (child.visit_count as f32);
+        let variance_bound = child.variance() + f32::sqrt(2.0 * log_ratio);
+        child.mean() + self.c * f32::sqrt(log_ratio * variance_bound.min(0.25))
+    }
+}
+
+/// PUCT: `mean + c * P(a) * sqrt(N) / (1 + n)`, with a prior per action.
+#[derive(Debug, Clone)]
+pub struct Puct {
+    pub c: f32,
+    /// Prior of each action, indexed by action; uniform if `None`.
+    pub priors: Option<Vec<f32>>,
+}
+
+impl SelectionPolicy for Puct {
+    fn score(&self, parent_visits: u32, child: &ChildStats) -> f32 {
+        let prior = match &self.priors {
+            Some(priors) => priors[child.action],
+            None => 1.0 / (child.action_n as f32),
+        };
+        let explore = prior * f32::sqrt(parent_visits as f32) / (1.0 + child.visit_count as f32);
+        child.mean() + self.c * explore
+    }
+
+    fn validate(&self, action_n: usize) -> Result<(), RmctsError> {
+        match &self.priors {
+            Some(priors) if priors.len() != action_n => Err(RmctsError::InvalidConfig(format!(
+                "PUCT has {} priors for {} actions",
+                priors.len(),
+                action_n
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Progressive widening over another policy: a node visited `N` times may have at
+/// most `ceil(k * N^alpha)` children, so large rule sets are searched deeply
+/// before they are searched widely.
+#[derive(Debug, Clone)]
+pub struct ProgressiveWidening {
+    pub policy: Arc<dyn SelectionPolicy>,
+    pub k: f32,
+    pub alpha: f32,
+}
+
+impl SelectionPolicy for ProgressiveWidening {
+    fn score(&self, parent_visits: u32, child: &ChildStats) -> f32 {
+        self.policy.score(parent_visits, child)
+    }
+
+    fn can_widen(&self, visit_count: u32, expanded: usize) -> bool {
+        let limit = (self.k * (visit_count.max(1) as f32).powf(self.alpha)).ceil();
+        (expanded as f32) < limit && self.policy.can_widen(visit_count, expanded)
+    }
+
+    fn validate(&self, action_n: usize) -> Result<(), RmctsError> {
+        self.policy.validate(action_n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn child(action: usize, visit_count: u32, q_sum: f32, q_sq_sum: f32) -> ChildStats {
+        ChildStats {
+            action,
+            action_n: 4,
+            visit_count,
+            complete_visit_count: visit_count,
+            q_sum,
+            q_sq_sum,
+        }
+    }
+
+    #[test]
+    fn test_ucb1_prefers_less_visited() {
+        let policy = Ucb1 { c: 2.0 };
+        let a = child(0, 10, 5.0, 2.5);
+        let b = child(1, 2, 1.0, 0.5);
+        assert!(policy.score(12, &b) > policy.score(12, &a));
+        assert_eq!(Ucb1 { c: 0.0 }.score(12, &a), 0.5);
+    }
+
+    #[test]
+    fn test_ucb1_tuned_explores_noisy_children() {
+        let policy = Ucb1Tuned { c: 1.0 };
+        let steady = child(0, 500, 250.0, 125.0);
+        let noisy = child(1, 500, 250.0, 500.0);
+        assert_eq!(steady.variance(), 0.0);
+        assert!(policy.score(1000, &noisy) > policy.score(1000, &steady));
+    }
+
+    #[test]
+    fn test_puct_follows_priors() {
+        let policy = Puct {
+            c: 1.0,
+            priors: Some(vec![0.7, 0.1, 0.1, 0.1]),
+        };
+        let a = child(0, 1, 0.0, 0.0);
+        let b = child(1, 1, 0.0, 0.0);
+        assert!(policy.score(4, &a) > policy.score(4, &b));
+    }
+
+    #[test]
+    fn test_puct_rejects_short_priors() {
+        let policy = Puct {
+            c: 1.0,
+            priors: Some(vec![0.5, 0.5]),
+        };
+        assert!(policy.validate(2).is_ok());
+        assert!(matches!(
+            policy.validate(3),
+            Err(RmctsError::InvalidConfig(_))
+        ));
+        let widened = ProgressiveWidening {
+            policy: Arc::new(policy),
+            k: 1.0,
+            alpha: 0.5,
+        };
+        assert!(widened.validate(3).is_err());
+    }
+
+    #[test]
+    fn test_merge_pools_visits() {
+        let mut a = child(0, 2, 2.0, 2.0);
+        a.merge(&child(0, 6, 0.0, 0.0));
+        assert_eq!(a.visit_count, 8);
+        assert_eq!(a.complete_visit_count, 8);
+        assert_eq!(a.mean(), 0.25);
+    }
+
+    #[test]
+    fn test_progressive_widening() {
+        let policy = ProgressiveWidening {
+            policy: Arc::new(Ucb1 { c: 2.0 }),
+            k: 1.0,
+            alpha: 0.5,
+        };
+        assert!(policy.can_widen(0, 0));
+        assert!(!policy.can_widen(1, 1));
+        assert!(policy.can_widen(9, 2));
+        assert!(!policy.can_widen(9, 3));
+    }
+}
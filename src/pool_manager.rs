@@ -1,11 +1,21 @@
+use crate::eg_env::EgraphEnv;
+use crate::error::RmctsError;
+use crate::reward::IntoReward;
+use crate::run::MCTSArgs;
 use crate::tree::{ExpTask, SimTask};
 use crate::workers::{worker_loop, Message, Reply};
 
 #[allow(unused_imports)]
 use egg::{Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, RecExpr, Rewrite};
 use std::marker::PhantomData;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+// how often a blocked planner checks for workers that died without replying
+pub(crate) const CRASH_POLL: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum Status {
@@ -15,78 +25,95 @@ enum Status {
 
 pub struct PoolManager<L, N, CF>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
-    #[allow(unused_variables, dead_code)]
     name: &'static str,
     work_num: usize,
+    deterministic: bool,
 
     // self
     workers: Vec<thread::JoinHandle<()>>,
     worker_status: Vec<Status>,
+    // order in which tasks were assigned, to reply in that order if deterministic
+    assigned_seq: Vec<u64>,
+    next_seq: u64,
+    // set to ask a worker to stop its current task early
+    cancel_flags: Vec<Arc<AtomicBool>>,
     txs: Vec<Sender<Message<L, N>>>,
-    rxs: Vec<Receiver<Reply<L, N>>>,
+    // replies of every worker, tagged with its id
+    rx: Receiver<(usize, Reply<L, N>)>,
+    // replies received while waiting for another worker, at most one per worker
+    buffered: Vec<Option<Reply<L, N>>>,
     d: PhantomData<CF>,
 }
 
 impl<L, N, CF> PoolManager<L, N, CF>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
     pub fn new(
         name: &'static str,
         work_num: usize,
-        gamma: f32,
-        max_sim_step: u32,
-        verbose: bool,
+        args: &MCTSArgs,
         egraph: EGraph<L, N>,
-        id: Id,
+        roots: &[Id],
         rules: Vec<Rewrite<L, N>>,
         cf: CF,
-        lp_extract: bool,
-        node_limit: usize,
-        time_limit: usize,
     ) -> Self {
         // build workers
         let mut workers = Vec::new();
         let mut txs = Vec::new();
-        let mut rxs = Vec::new();
+        let mut cancel_flags = Vec::new();
+        let (reply_tx, rx) = mpsc::channel();
         for i in 0..work_num {
-            let (w, tx, rx) = worker_loop(
+            let cancel = Arc::new(AtomicBool::new(false));
+            let (egraph, roots, rules, cf) =
+                (egraph.clone(), roots.to_vec(), rules.clone(), cf.clone());
+            let env_args = args.clone();
+            let (w, tx) = worker_loop(
                 i,
-                gamma,
-                max_sim_step,
-                verbose,
-                egraph.clone(),
-                id.clone(),
-                rules.clone(),
-                cf.clone(),
-                lp_extract,
-                node_limit,
-                time_limit,
+                args.clone(),
+                Arc::clone(&cancel),
+                reply_tx.clone(),
+                move || EgraphEnv::from_args(egraph, roots, rules, cf, &env_args),
             );
             workers.push(w);
             txs.push(tx);
-            rxs.push(rx);
+            cancel_flags.push(cancel);
         }
 
         PoolManager {
             name: name,
             work_num: work_num,
+            deterministic: args.deterministic,
             workers: workers,
             worker_status: vec![Status::Idle; work_num],
+            assigned_seq: vec![0; work_num],
+            next_seq: 0,
+            cancel_flags,
             txs: txs,
-            rxs: rxs,
+            rx,
+            buffered: (0..work_num).map(|_| None).collect(),
             d: PhantomData,
         }
     }
@@ -100,24 +127,36 @@ where
         exp_task: ExpTask<L, N>,
         global_saving_idx: u32,
         task_idx: u32,
-    ) {
+    ) -> Result<(), RmctsError> {
         let id = self.find_idle_worker();
-        self.txs[id]
-            .send(Message::Expansion(exp_task, global_saving_idx, task_idx))
-            .unwrap();
+        self.send(
+            id,
+            Message::Expansion(exp_task, global_saving_idx, task_idx),
+        )
     }
 
-    pub fn assign_simulation_task(&mut self, sim_task: SimTask<L, N>, task_idx: u32) {
+    pub fn assign_simulation_task(
+        &mut self,
+        sim_task: SimTask<L, N>,
+        task_idx: u32,
+    ) -> Result<(), RmctsError> {
         let id = self.find_idle_worker();
-        self.txs[id]
-            .send(Message::Simulation(sim_task, task_idx))
-            .unwrap();
+        self.send(id, Message::Simulation(sim_task, task_idx))
     }
 
     #[allow(dead_code)]
-    pub fn assign_nothing_task(&mut self) {
+    pub fn assign_nothing_task(&mut self) -> Result<(), RmctsError> {
         let id = self.find_idle_worker();
-        self.txs[id].send(Message::Nothing).unwrap();
+        self.send(id, Message::Nothing)
+    }
+
+    fn send(&mut self, id: usize, message: Message<L, N>) -> Result<(), RmctsError> {
+        self.cancel_flags[id].store(false, Ordering::Relaxed);
+        self.txs[id].send(message).map_err(|_| self.crashed(id))
+    }
+
+    fn crashed(&self, id: usize) -> RmctsError {
+        RmctsError::WorkerCrashed(format!("{} worker {}", self.name, id))
     }
 
     fn find_idle_worker(&mut self) -> usize {
@@ -126,6 +165,8 @@ where
                 Status::Busy => (),
                 Status::Idle => {
                     self.worker_status[i] = Status::Busy;
+                    self.assigned_seq[i] = self.next_seq;
+                    self.next_seq += 1;
                     return i;
                 }
             }
@@ -133,6 +174,21 @@ where
         unreachable!("no idle worker");
     }
 
+    /// Ask every busy worker to wrap up its current task; simulations then reply
+    /// early with [`Reply::TimedOutSimulation`].
+    pub fn cancel_all(&self) {
+        for (id, status) in self.worker_status.iter().enumerate() {
+            if status == &Status::Busy {
+                self.cancel_flags[id].store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether no worker has a task.
+    pub fn is_idle(&self) -> bool {
+        !self.worker_status.contains(&Status::Busy)
+    }
+
     pub fn occupancy(&mut self) -> f32 {
         (self
             .worker_status
@@ -141,136 +197,236 @@ where
             / (self.work_num as f32)
     }
 
-    pub fn get_complete_task(&mut self) -> Reply<L, N> {
+    pub fn get_complete_task(&mut self) -> Result<Reply<L, N>, RmctsError> {
+        if self.deterministic {
+            // block on the oldest task so results are applied in assignment order
+            let id = (0..self.work_num)
+                .filter(|&i| self.worker_status[i] == Status::Busy)
+                .min_by_key(|&i| self.assigned_seq[i])
+                .expect("no busy worker");
+            let reply = self.recv(id)?;
+            self.worker_status[id] = Status::Idle;
+            return Ok(reply);
+        }
+        let (id, reply) = match self.buffered.iter().position(Option::is_some) {
+            Some(id) => (id, self.buffered[id].take().unwrap()),
+            None => self.recv_any()?,
+        };
+        self.worker_status[id] = Status::Idle;
+        Ok(reply)
+    }
+
+    /// Block until worker `id` replies, keeping the replies of other workers.
+    fn recv(&mut self, id: usize) -> Result<Reply<L, N>, RmctsError> {
+        if let Some(reply) = self.buffered[id].take() {
+            return Ok(reply);
+        }
+        loop {
+            let (from, reply) = self.recv_any()?;
+            if from == id {
+                return Ok(reply);
+            }
+            self.buffered[from] = Some(reply);
+        }
+    }
+
+    /// Block until any worker replies; a worker that failed, or exited without
+    /// replying to its task, is an error.
+    fn recv_any(&mut self) -> Result<(usize, Reply<L, N>), RmctsError> {
         loop {
-            for i in 0..self.work_num {
-                let reply = self.rxs[i].try_recv(); // non-blocking
-                match reply {
-                    Err(_) => (),
-                    Ok(r) => {
-                        self.worker_status[i] = Status::Idle;
-                        return r;
+            match self.rx.recv_timeout(CRASH_POLL) {
+                Ok((_, Reply::Failed(e))) => return Err(e),
+                Ok(reply) => return Ok(reply),
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(id) = (0..self.work_num).find(|&id| {
+                        self.worker_status[id] == Status::Busy
+                            && self.buffered[id].is_none()
+                            && self.workers[id].is_finished()
+                    }) {
+                        // its reply may have raced with the exit
+                        if let Ok(reply) = self.rx.try_recv() {
+                            match reply {
+                                (_, Reply::Failed(e)) => return Err(e),
+                                reply => return Ok(reply),
+                            }
+                        }
+                        return Err(self.crashed(id));
                     }
                 }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // every worker is gone
+                    let id = (0..self.work_num)
+                        .find(|&id| self.worker_status[id] == Status::Busy)
+                        .unwrap_or(0);
+                    return Err(self.crashed(id));
+                }
             }
         }
     }
 
-    pub fn wait_until_all_idle(&mut self) {
+    pub fn wait_until_all_idle(&mut self) -> Result<(), RmctsError> {
         for id in 0..self.work_num {
             match self.worker_status[id] {
                 Status::Idle => (),
                 Status::Busy => {
-                    self.rxs[id].recv().unwrap(); // block until workers finish
+                    self.recv(id)?; // block until workers finish
                     self.worker_status[id] = Status::Idle;
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn close(&mut self) {
+    pub fn close(&mut self) -> Result<(), RmctsError> {
         // wait until all exit
+        self.wait_until_all_idle()?;
         for id in 0..self.work_num {
-            match self.worker_status[id] {
-                Status::Idle => self.txs[id].send(Message::Exit).unwrap(),
-                Status::Busy => {
-                    self.rxs[id].recv().unwrap(); // block until workers finish
-                    self.txs[id].send(Message::Exit).unwrap();
-                    self.worker_status[id] = Status::Idle;
-                }
-            }
+            self.send(id, Message::Exit)?;
         }
         // join
-        while self.workers.len() > 0 {
-            let w = self.workers.remove(0); // get ownership
-            w.join().unwrap();
+        let name = self.name;
+        for (id, w) in self.workers.drain(..).enumerate() {
+            w.join()
+                .map_err(|_| RmctsError::WorkerCrashed(format!("{} worker {}", name, id)))?;
         }
+        Ok(())
     }
 }
 
-// #[cfg(test)]
-// mod test {
-//     #![allow(unused_imports)]
-//     // use super::{PoolManager, worker_loop};
-//     use super::*;
-//     // use std::sync::atomic::{AtomicUsize, Ordering};
-//     // use std::sync::mpsc::{channel, sync_channel};
-//     // use std::sync::{Arc, Barrier};
-//     use egg::*;
-//     use std::thread::sleep;
-//     use std::time::Duration;
-//
-//     define_language! {
-//         enum SimpleLanguage {
-//             Num(i32),
-//             "+" = Add([Id; 2]),
-//             "*" = Mul([Id; 2]),
-//             Symbol(Symbol),
-//         }
-//     }
-//
-//     fn make_rules() -> Vec<Rewrite<SimpleLanguage, ()>> {
-//         vec![
-//             rewrite!("commute-add"; "(+ ?a ?b)" => "(+ ?b ?a)"),
-//             rewrite!("commute-mul"; "(* ?a ?b)" => "(* ?b ?a)"),
-//             rewrite!("add-0"; "(+ ?a 0)" => "?a"),
-//             rewrite!("mul-0"; "(* ?a 0)" => "0"),
-//             rewrite!("mul-1"; "(* ?a 1)" => "?a"),
-//         ]
-//     }
-//
-//     const NODE_LIMIT: usize = 10000;
-//     const TIME_LIMIT: usize = 10;
-//
-//     #[test]
-//     fn test_build_and_close() {
-//         let mut pool = PoolManager::new(
-//             "test",
-//             1,
-//             1.0,
-//             1,
-//             true,
-//             "(* 0 42)".parse().unwrap(),
-//             make_rules(),
-//             NODE_LIMIT,
-//             TIME_LIMIT,
-//         );
-//         assert_eq!(pool.has_idle_server(), true);
-//         pool.assign_nothing_task();
-//         println!("occupancy: {}", pool.occupancy());
-//         pool.get_complete_task();
-//         println!("after occupancy: {}", pool.occupancy());
-//
-//         // let a = vec![1, 2, 3];
-//         // for (i, j) in a.iter().enumerate() {
-//         //     println!("{} - {}", i, j);
-//         // }
-//         thread::sleep(Duration::from_secs(1));
-//
-//         pool.close();
-//     }
-//
-//     #[test]
-//     fn test_poll_channel() {
-//         let worker_num = 5;
-//         let mut pool = PoolManager::new(
-//             "test",
-//             worker_num,
-//             1.0,
-//             1,
-//             true,
-//             "(* 0 42)".parse().unwrap(),
-//             make_rules(),
-//             NODE_LIMIT,
-//             TIME_LIMIT,
-//         );
-//         pool.assign_nothing_task();
-//         pool.assign_nothing_task();
-//         pool.assign_nothing_task();
-//         pool.assign_nothing_task();
-//
-//         println!("occupancy: {}", pool.occupancy());
-//         pool.close();
-//         println!("test_pool done");
-//     }
-// }
+impl<L, N, CF> Drop for PoolManager<L, N, CF>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    fn drop(&mut self) {
+        // if `close` was not reached (e.g. planning failed), let the workers exit on
+        // their own once they finish their current task instead of leaking them
+        if !self.workers.is_empty() {
+            for tx in self.txs.iter() {
+                let _ = tx.send(Message::Exit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::action::Action;
+    use crate::eg_env::{Ckpt, EgraphEnv};
+    use crate::test_util::{make_egraph, make_rules, AstSize};
+    use egg::SymbolLang;
+
+    fn make_pool(work_num: usize, args: &MCTSArgs) -> PoolManager<SymbolLang, (), AstSize> {
+        let (egraph, root) = make_egraph("(+ 0 (* foo 1))");
+        PoolManager::new(
+            "test",
+            work_num,
+            args,
+            egraph,
+            &[root],
+            make_rules(),
+            AstSize,
+        )
+    }
+
+    fn make_ckpt() -> Ckpt<SymbolLang, ()> {
+        let (egraph, root) = make_egraph("(+ 0 (* foo 1))");
+        let args = MCTSArgs::default();
+        let mut env =
+            EgraphEnv::from_args(egraph, vec![root], make_rules(), AstSize, &args).unwrap();
+        env.reset();
+        env.checkpoint()
+    }
+
+    fn sim_task(seed: u64) -> SimTask<SymbolLang, ()> {
+        SimTask::new(make_ckpt(), 0, 0, false, seed)
+    }
+
+    #[test]
+    fn test_build_and_close() {
+        let mut pool = make_pool(2, &MCTSArgs::default());
+        assert!(pool.has_idle_server());
+        assert_eq!(pool.occupancy(), 0.0);
+        pool.assign_nothing_task().unwrap();
+        pool.assign_nothing_task().unwrap();
+        assert!(!pool.has_idle_server());
+        assert_eq!(pool.occupancy(), 1.0);
+        for _ in 0..2 {
+            assert!(matches!(pool.get_complete_task(), Ok(Reply::OK)));
+        }
+        assert_eq!(pool.occupancy(), 0.0);
+        pool.close().unwrap();
+    }
+
+    #[test]
+    fn test_replies_are_tagged() {
+        let mut pool = make_pool(3, &MCTSArgs::default());
+        for task_idx in 0..3 {
+            pool.assign_simulation_task(sim_task(task_idx as u64), task_idx)
+                .unwrap();
+        }
+        let mut done = vec![];
+        for _ in 0..3 {
+            match pool.get_complete_task() {
+                Ok(Reply::DoneSimulation(task_idx, _)) => done.push(task_idx),
+                _ => panic!("expected a simulation reply"),
+            }
+        }
+        done.sort();
+        assert_eq!(done, vec![0, 1, 2]);
+        assert!(pool.has_idle_server());
+        assert_eq!(pool.occupancy(), 0.0);
+        pool.close().unwrap();
+    }
+
+    #[test]
+    fn test_deterministic_replies_in_order() {
+        let args = MCTSArgs {
+            deterministic: true,
+            ..Default::default()
+        };
+        let mut pool = make_pool(3, &args);
+        // the straggler sleeps for a random time, later replies still wait for it
+        pool.assign_nothing_task().unwrap();
+        for task_idx in 1..3 {
+            pool.assign_simulation_task(sim_task(task_idx as u64), task_idx)
+                .unwrap();
+        }
+        assert!(matches!(pool.get_complete_task(), Ok(Reply::OK)));
+        for task_idx in 1..3 {
+            match pool.get_complete_task() {
+                Ok(Reply::DoneSimulation(idx, _)) => assert_eq!(idx, task_idx),
+                _ => panic!("expected a simulation reply"),
+            }
+        }
+        pool.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_worker_is_an_error() {
+        // every worker fails to build its env and exits
+        let args = MCTSArgs {
+            actions: Some(vec![Action {
+                name: "missing".to_string(),
+                rules: vec![99],
+                iter_limit: 1,
+            }]),
+            ..Default::default()
+        };
+        let mut pool = make_pool(1, &args);
+        let result = pool
+            .assign_nothing_task()
+            .and_then(|_| pool.get_complete_task());
+        assert!(result.is_err());
+    }
+}
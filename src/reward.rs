@@ -0,0 +1,136 @@
+use ordered_float::NotNan;
+
+/// Conversion of a [`egg::CostFunction::Cost`] into the `f64` scale rewards are
+/// computed on; lower is better, as for egg costs.
+///
+/// Implement this for your own cost type to plug a custom cost model (e.g. a
+/// latency estimate) into the search. Tuples have no impl, as egg ranks them
+/// lexicographically: wrap such a cost in a type of your own whose reward keeps
+/// that order, e.g. by weighting each component above the range of the next.
+pub trait IntoReward {
+    fn into_reward(self) -> f64;
+}
+
+macro_rules! impl_into_reward {
+    ($($t:ty),*) => {
+        $(
+            impl IntoReward for $t {
+                fn into_reward(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_into_reward!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+impl IntoReward for NotNan<f32> {
+    fn into_reward(self) -> f64 {
+        self.into_inner() as f64
+    }
+}
+
+impl IntoReward for NotNan<f64> {
+    fn into_reward(self) -> f64 {
+        self.into_inner()
+    }
+}
+
+/// How the costs of several roots are combined into the cost of an e-graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RootCost {
+    /// Sum of the cost of each root's best expression.
+    #[default]
+    Sum,
+    /// DAG cost of all best expressions together, so sub-expressions shared between
+    /// roots are paid for once.
+    SharedDag,
+}
+
+/// How [`crate::run::run_mcts`] turns a change of cost into a step reward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RewardFn {
+    /// `last_cost - cost`; negative when a step increases the cost.
+    #[default]
+    AbsoluteDelta,
+    /// `(last_cost - cost) / base_cost`, comparable across inputs of different size.
+    RelativeImprovement,
+    /// `ln(last_cost / cost)`; 0 unless both costs are positive.
+    LogRatio,
+    /// 0 on every step but the last, which is rewarded `base_cost - cost`.
+    TerminalOnly,
+}
+
+impl RewardFn {
+    /// Reward of a step that moved the cost from `last_cost` to `cost`; `done` marks
+    /// the last step of an episode.
+    pub fn step_reward(&self, base_cost: f64, last_cost: f64, cost: f64, done: bool) -> f32 {
+        let reward = match self {
+            RewardFn::AbsoluteDelta => last_cost - cost,
+            RewardFn::RelativeImprovement => {
+                if base_cost == 0.0 {
+                    last_cost - cost
+                } else {
+                    (last_cost - cost) / base_cost.abs()
+                }
+            }
+            RewardFn::LogRatio => {
+                if last_cost > 0.0 && cost > 0.0 {
+                    (last_cost / cost).ln()
+                } else {
+                    0.0
+                }
+            }
+            RewardFn::TerminalOnly => {
+                if done {
+                    base_cost - cost
+                } else {
+                    0.0
+                }
+            }
+        };
+        reward as f32
+    }
+
+    /// Extra reward when an episode is cut short at `cost` without reaching a
+    /// terminal state, so terminal-only shaping still sees the outcome.
+    pub fn truncation_reward(&self, base_cost: f64, cost: f64) -> f32 {
+        match self {
+            RewardFn::TerminalOnly => (base_cost - cost) as f32,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reward_fn_signed() {
+        assert_eq!(
+            RewardFn::AbsoluteDelta.step_reward(10.0, 4.0, 6.0, false),
+            -2.0
+        );
+        assert_eq!(
+            RewardFn::AbsoluteDelta.step_reward(10.0, 6.0, 4.0, false),
+            2.0
+        );
+        assert_eq!(
+            RewardFn::RelativeImprovement.step_reward(10.0, 6.0, 4.0, false),
+            0.2
+        );
+        assert!(RewardFn::LogRatio.step_reward(10.0, 4.0, 8.0, false) < 0.0);
+        assert_eq!(RewardFn::LogRatio.step_reward(10.0, 4.0, 0.0, false), 0.0);
+    }
+
+    #[test]
+    fn test_reward_fn_terminal_only() {
+        let rf = RewardFn::TerminalOnly;
+        assert_eq!(rf.step_reward(10.0, 6.0, 4.0, false), 0.0);
+        assert_eq!(rf.step_reward(10.0, 6.0, 4.0, true), 6.0);
+        assert_eq!(rf.truncation_reward(10.0, 4.0), 6.0);
+        assert_eq!(RewardFn::AbsoluteDelta.truncation_reward(10.0, 4.0), 0.0);
+    }
+}
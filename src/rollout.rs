@@ -0,0 +1,277 @@
+use crate::error::RmctsError;
+use rand::{Rng, RngCore};
+use std::fmt::Debug;
+
+/// The state of a rollout, as seen by a [`RolloutPolicy`].
+pub trait RolloutContext {
+    /// Number of rules.
+    fn action_n(&self) -> usize;
+    /// Steps taken so far in this rollout.
+    fn step_count(&self) -> u32;
+    /// Whether each rule was applied since the e-graph last changed and saturated,
+    /// i.e. applying it again is known to be a no-op.
+    fn saturated(&self) -> &[bool];
+    /// Whether each rule can change the e-graph, see [`crate::run::MCTSArgs::action_mask`].
+    fn legal_actions(&self) -> &[bool];
+    /// Reward of applying `action` to the current state; the state is restored
+    /// afterwards, so this costs a full e-graph clone and egg run.
+    fn peek_reward(&mut self, action: usize) -> Result<f32, RmctsError>;
+    /// Random source of the rollout, seeded per simulation task.
+    fn rng(&mut self) -> &mut dyn RngCore;
+}
+
+/// Default policy of the simulation workers: picks the rule of each rollout step.
+pub trait RolloutPolicy: Debug + Send + Sync {
+    fn select(&self, ctx: &mut dyn RolloutContext) -> Result<usize, RmctsError>;
+}
+
+/// Uniformly random legal rules, as in MCTS-GEB.
+#[derive(Debug, Clone)]
+pub struct Uniform;
+
+impl RolloutPolicy for Uniform {
+    fn select(&self, ctx: &mut dyn RolloutContext) -> Result<usize, RmctsError> {
+        let candidates: Vec<usize> = (0..ctx.action_n())
+            .filter(|&action| ctx.legal_actions()[action])
+            .collect();
+        if candidates.is_empty() {
+            return Err(RmctsError::NoLegalAction);
+        }
+        Ok(candidates[ctx.rng().gen_range(0..candidates.len())])
+    }
+}
+
+/// Random legal rules drawn proportionally to a weight per rule, indexed by action.
+#[derive(Debug, Clone)]
+pub struct RuleWeighted {
+    pub weights: Vec<f32>,
+}
+
+impl RolloutPolicy for RuleWeighted {
+    fn select(&self, ctx: &mut dyn RolloutContext) -> Result<usize, RmctsError> {
+        if self.weights.len() != ctx.action_n() {
+            return Err(RmctsError::InvalidConfig(format!(
+                "{} rollout weights for {} rules",
+                self.weights.len(),
+                ctx.action_n()
+            )));
+        }
+        let weights: Vec<f32> = self
+            .weights
+            .iter()
+            .zip(ctx.legal_actions())
+            .map(|(weight, legal)| if *legal { *weight } else { 0.0 })
+            .collect();
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            return Uniform.select(ctx);
+        }
+        let mut target = ctx.rng().gen_range(0.0..1.0) * total;
+        let mut last = 0;
+        for (action, weight) in weights.iter().enumerate() {
+            if *weight > 0.0 {
+                if target < *weight {
+                    return Ok(action);
+                }
+                last = action;
+            }
+            target -= weight;
+        }
+        Ok(last)
+    }
+}
+
+/// With probability `epsilon` a uniformly random rule, otherwise try every legal
+/// rule and keep the one with the largest reward, i.e. the largest cost drop.
+#[derive(Debug, Clone)]
+pub struct EpsilonGreedy {
+    pub epsilon: f32,
+}
+
+impl RolloutPolicy for EpsilonGreedy {
+    fn select(&self, ctx: &mut dyn RolloutContext) -> Result<usize, RmctsError> {
+        if ctx.rng().gen_range(0.0..1.0) < self.epsilon {
+            return Uniform.select(ctx);
+        }
+        let mut best_action = None;
+        let mut best_reward = f32::MIN;
+        for action in 0..ctx.action_n() {
+            if !ctx.legal_actions()[action] || ctx.saturated()[action] {
+                continue;
+            }
+            let reward = ctx.peek_reward(action)?;
+            if reward > best_reward {
+                best_reward = reward;
+                best_action = Some(action);
+            }
+        }
+        match best_action {
+            Some(action) => Ok(action),
+            None => Uniform.select(ctx),
+        }
+    }
+}
+
+/// Uniformly random among the legal rules not known to be saturated at the current
+/// state, which matters without the action mask; uniform over the legal rules if
+/// every one is saturated.
+#[derive(Debug, Clone)]
+pub struct SkipSaturated;
+
+impl RolloutPolicy for SkipSaturated {
+    fn select(&self, ctx: &mut dyn RolloutContext) -> Result<usize, RmctsError> {
+        let candidates: Vec<usize> = (0..ctx.action_n())
+            .filter(|&action| ctx.legal_actions()[action] && !ctx.saturated()[action])
+            .collect();
+        if candidates.is_empty() {
+            return Uniform.select(ctx);
+        }
+        Ok(candidates[ctx.rng().gen_range(0..candidates.len())])
+    }
+}
+
+/// How the returns of the rollouts of one leaf are combined, see
+/// [`crate::run::MCTSArgs::rollouts_per_leaf`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RolloutAggregation {
+    /// Mean return, an estimate of the leaf value under the rollout policy.
+    #[default]
+    Mean,
+    /// Best return, an estimate of the value reachable from the leaf.
+    Max,
+}
+
+impl RolloutAggregation {
+    pub fn aggregate(&self, returns: &[f32]) -> f32 {
+        match self {
+            RolloutAggregation::Mean => returns.iter().sum::<f32>() / returns.len() as f32,
+            RolloutAggregation::Max => returns.iter().copied().fold(f32::MIN, f32::max),
+        }
+    }
+}
+
+/// Summary of the state a simulation starts from, see [`ValueEstimator`].
+#[derive(Debug, Clone)]
+pub struct StateSummary {
+    pub base_cost: f64,
+    pub cost: f64,
+    pub egraph_nodes: usize,
+    pub egraph_classes: usize,
+}
+
+/// Estimate `V(s)` of the state a simulation starts from. The return of the
+/// simulation is `factor * rollout_return + (1 - factor) * V(s)`, which
+/// stabilises noisy rollouts.
+pub trait ValueEstimator: Debug + Send + Sync {
+    fn value(&self, state: &StateSummary) -> f32;
+}
+
+/// Expects a further cost drop of `scale` times the current cost, on the scale of
+/// [`crate::reward::RewardFn::AbsoluteDelta`].
+#[derive(Debug, Clone)]
+pub struct ProportionalValue {
+    pub scale: f32,
+}
+
+impl ValueEstimator for ProportionalValue {
+    fn value(&self, state: &StateSummary) -> f32 {
+        self.scale * state.cost as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // pretend rule `a` drops the cost by `rewards[a]`
+    struct FakeContext {
+        rewards: Vec<f32>,
+        saturated: Vec<bool>,
+        legal_actions: Vec<bool>,
+        rng: ChaCha8Rng,
+    }
+
+    impl RolloutContext for FakeContext {
+        fn action_n(&self) -> usize {
+            self.rewards.len()
+        }
+        fn step_count(&self) -> u32 {
+            0
+        }
+        fn saturated(&self) -> &[bool] {
+            &self.saturated
+        }
+        fn legal_actions(&self) -> &[bool] {
+            &self.legal_actions
+        }
+        fn peek_reward(&mut self, action: usize) -> Result<f32, RmctsError> {
+            Ok(self.rewards[action])
+        }
+        fn rng(&mut self) -> &mut dyn RngCore {
+            &mut self.rng
+        }
+    }
+
+    fn ctx(saturated: Vec<bool>) -> FakeContext {
+        FakeContext {
+            rewards: vec![1.0, 3.0, 2.0],
+            saturated,
+            legal_actions: vec![true, true, true],
+            rng: ChaCha8Rng::seed_from_u64(0),
+        }
+    }
+
+    #[test]
+    fn test_epsilon_greedy_keeps_best_drop() {
+        let policy = EpsilonGreedy { epsilon: 0.0 };
+        assert_eq!(policy.select(&mut ctx(vec![false; 3])).unwrap(), 1);
+        assert_eq!(
+            policy.select(&mut ctx(vec![false, true, false])).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_skip_saturated() {
+        let mut ctx = ctx(vec![true, false, true]);
+        for _ in 0..20 {
+            assert_eq!(SkipSaturated.select(&mut ctx).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_rule_weighted() {
+        let mut ctx = ctx(vec![false; 3]);
+        let policy = RuleWeighted {
+            weights: vec![0.0, 1.0, 0.0],
+        };
+        for _ in 0..20 {
+            assert_eq!(policy.select(&mut ctx).unwrap(), 1);
+        }
+        let policy = RuleWeighted { weights: vec![1.0] };
+        assert!(policy.select(&mut ctx).is_err());
+    }
+
+    #[test]
+    fn test_rollout_aggregation() {
+        let returns = [1.0, 4.0, 1.0];
+        assert_eq!(RolloutAggregation::Mean.aggregate(&returns), 2.0);
+        assert_eq!(RolloutAggregation::Max.aggregate(&returns), 4.0);
+        assert_eq!(RolloutAggregation::Max.aggregate(&[-1.0]), -1.0);
+    }
+
+    #[test]
+    fn test_rollouts_respect_legal_actions() {
+        let mut ctx = ctx(vec![false; 3]);
+        ctx.legal_actions = vec![true, false, false];
+        for _ in 0..20 {
+            assert_eq!(Uniform.select(&mut ctx).unwrap(), 0);
+        }
+        let policy = EpsilonGreedy { epsilon: 0.0 };
+        assert_eq!(policy.select(&mut ctx).unwrap(), 0);
+        ctx.legal_actions = vec![false; 3];
+        assert_eq!(Uniform.select(&mut ctx), Err(RmctsError::NoLegalAction));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::eg_env::{Ckpt, EgraphEnv};
+use crate::error::RmctsError;
+use crate::policy::ChildStats;
+use crate::pool_manager::CRASH_POLL;
+use crate::reward::IntoReward;
+use crate::run::MCTSArgs;
+use crate::tree::Tree;
+
+use egg::{Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, Rewrite};
+use log::debug;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+
+pub enum PlanMessage<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    Exit,
+    // plan from the state and reply with the root statistics
+    Plan(Ckpt<L, N>),
+    // the action taken after planning
+    Advance(usize),
+}
+
+type PlanReply = (usize, Result<(Vec<Option<ChildStats>>, usize), RmctsError>);
+
+/// The extra trees of root-parallel MCTS, see [`MCTSArgs::root_parallel`].
+///
+/// Each runs on its own planner thread, with its own seed and worker pools, and
+/// searches from the same state as the main tree.
+pub struct RootParallel<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    planners: Vec<thread::JoinHandle<()>>,
+    txs: Vec<Sender<PlanMessage<L, N>>>,
+    rx: Receiver<PlanReply>,
+    // last peak memory reported by each planner
+    peak_memory: Vec<usize>,
+}
+
+impl<L, N> RootParallel<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    /// Start `args.root_parallel - 1` planners; the main tree is the remaining one.
+    pub fn new<CF>(
+        args: &MCTSArgs,
+        egraph: EGraph<L, N>,
+        roots: &[Id],
+        rules: Vec<Rewrite<L, N>>,
+        cf: CF,
+    ) -> Self
+    where
+        CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+        <CF as CostFunction<L>>::Cost: IntoReward,
+    {
+        let (reply_tx, rx) = mpsc::channel();
+        let mut planners = Vec::new();
+        let mut txs = Vec::new();
+        for id in 0..args.root_parallel - 1 {
+            let args = MCTSArgs {
+                // the main tree keeps the given seed
+                seed: args.seed.map(|seed| seed.wrapping_add(id as u64 + 1)),
+                root_parallel: 1,
+                ..args.clone()
+            };
+            let (tx, planner_rx) = mpsc::channel();
+            let reply_tx = reply_tx.clone();
+            let egraph = egraph.clone();
+            let roots = roots.to_vec();
+            let rules = rules.clone();
+            let cf = cf.clone();
+            planners.push(thread::spawn(move || {
+                // the tree is not Send, so it is built on the planner thread
+                let tree = Tree::new(&args, egraph.clone(), &roots, rules.clone(), cf.clone());
+                let env = EgraphEnv::from_args(egraph, roots, rules, cf, &args);
+                planner_loop(id, tree, env, planner_rx, reply_tx)
+            }));
+            txs.push(tx);
+        }
+        RootParallel {
+            peak_memory: vec![0; planners.len()],
+            planners,
+            txs,
+            rx,
+        }
+    }
+
+    /// Let every planner search from `checkpoint_data`; collect the results with
+    /// [`RootParallel::collect`].
+    pub fn plan(&self, checkpoint_data: &Ckpt<L, N>) -> Result<(), RmctsError> {
+        for (id, tx) in self.txs.iter().enumerate() {
+            tx.send(PlanMessage::Plan(checkpoint_data.clone()))
+                .map_err(|_| crashed(id))?;
+        }
+        Ok(())
+    }
+
+    /// Block until every planner has searched, and merge their root statistics
+    /// into `stats` in planner order, so the float sums do not depend on which
+    /// planner finished first.
+    pub fn collect(&mut self, stats: &mut [Option<ChildStats>]) -> Result<(), RmctsError> {
+        let mut replies: Vec<_> = (0..self.txs.len()).map(|_| None).collect();
+        for _ in 0..self.txs.len() {
+            let (id, reply) = self.recv_any(&replies)?;
+            replies[id] = Some(reply?);
+        }
+        for (id, reply) in replies.into_iter().enumerate() {
+            let (other, peak_memory) = reply.ok_or_else(|| crashed(id))?;
+            merge_root_stats(stats, other);
+            self.peak_memory[id] = peak_memory;
+        }
+        Ok(())
+    }
+
+    /// Block until any planner replies; a planner that exited without replying
+    /// to the plan is an error, as for the workers of a [`PoolManager`].
+    ///
+    /// [`PoolManager`]: crate::pool_manager::PoolManager
+    fn recv_any<T>(&self, replies: &[Option<T>]) -> Result<PlanReply, RmctsError> {
+        let pending = |id: &usize| replies[*id].is_none();
+        loop {
+            match self.rx.recv_timeout(CRASH_POLL) {
+                Ok(reply) => return Ok(reply),
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(id) = (0..self.planners.len())
+                        .filter(pending)
+                        .find(|&id| self.planners[id].is_finished())
+                    {
+                        // its reply may have raced with the exit
+                        if let Ok(reply) = self.rx.try_recv() {
+                            return Ok(reply);
+                        }
+                        return Err(crashed(id));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // every planner is gone
+                    return Err((0..self.planners.len()).find(pending).map_or_else(
+                        || RmctsError::WorkerCrashed("root-parallel planners".to_string()),
+                        crashed,
+                    ));
+                }
+            }
+        }
+    }
+
+    pub fn advance_root(&self, action: usize) {
+        for tx in self.txs.iter() {
+            // a planner that is gone reports on the next plan
+            let _ = tx.send(PlanMessage::Advance(action));
+        }
+    }
+
+    /// Peak memory summed over the planners.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory.iter().sum()
+    }
+
+    pub fn close(&mut self) -> Result<(), RmctsError> {
+        for tx in self.txs.iter() {
+            let _ = tx.send(PlanMessage::Exit);
+        }
+        for (id, planner) in self.planners.drain(..).enumerate() {
+            planner.join().map_err(|_| crashed(id))?;
+        }
+        Ok(())
+    }
+}
+
+impl<L, N> Drop for RootParallel<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    fn drop(&mut self) {
+        // as for the worker pools, let the planners exit if `close` was not reached
+        for tx in self.txs.iter() {
+            let _ = tx.send(PlanMessage::Exit);
+        }
+    }
+}
+
+fn crashed(id: usize) -> RmctsError {
+    RmctsError::WorkerCrashed(format!("root-parallel planner {}", id))
+}
+
+fn planner_loop<L, N, CF>(
+    id: usize,
+    mut tree: Tree<L, N, CF>,
+    env: Result<EgraphEnv<L, N, CF>, RmctsError>,
+    rx: Receiver<PlanMessage<L, N>>,
+    tx: Sender<PlanReply>,
+) where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    let mut env = match env {
+        Ok(env) => env,
+        Err(e) => {
+            let _ = tx.send((id, Err(e)));
+            return;
+        }
+    };
+    while let Ok(message) = rx.recv() {
+        match message {
+            PlanMessage::Exit => break,
+            PlanMessage::Plan(checkpoint_data) => {
+                let stats = env.restore(checkpoint_data).and_then(|_| tree.search(&env));
+                debug!("root-parallel planner {} done", id);
+                let failed = stats.is_err();
+                if tx
+                    .send((id, stats.map(|s| (s, tree.peak_memory()))))
+                    .is_err()
+                    || failed
+                {
+                    break;
+                }
+            }
+            PlanMessage::Advance(action) => tree.advance_root(action),
+        }
+    }
+    let _ = tree.close();
+}
+
+/// Pool the root statistics of another tree into `stats`, action by action.
+pub fn merge_root_stats(stats: &mut [Option<ChildStats>], other: Vec<Option<ChildStats>>) {
+    for (stats, other) in stats.iter_mut().zip(other) {
+        match (stats.as_mut(), other) {
+            (Some(stats), Some(other)) => stats.merge(&other),
+            (None, Some(other)) => *stats = Some(other),
+            (_, None) => (),
+        }
+    }
+}
+
+/// The child with the best mean return, as [`crate::node::Node::select_uct_action`]
+/// with `max`.
+pub fn best_mean_action(stats: &[Option<ChildStats>]) -> Result<usize, RmctsError> {
+    let mut best_score = f32::MIN;
+    let mut best_action = None;
+    for stats in stats.iter().flatten() {
+        if stats.mean() > best_score {
+            best_score = stats.mean();
+            best_action = Some(stats.action);
+        }
+    }
+    let best_action = best_action.ok_or(RmctsError::NoLegalAction)?;
+    debug!(
+        "best_action {} and expected score {} over the merged trees",
+        best_action, best_score
+    );
+    Ok(best_action)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use egg::SymbolLang;
+
+    fn child(action: usize, visit_count: u32, q_sum: f32) -> Option<ChildStats> {
+        Some(ChildStats {
+            action,
+            action_n: 3,
+            visit_count,
+            complete_visit_count: visit_count,
+            q_sum,
+            q_sq_sum: 0.0,
+        })
+    }
+
+    #[test]
+    fn test_merge_root_stats() {
+        // each tree alone prefers a different action
+        let mut stats = vec![child(0, 1, 3.0), child(1, 9, 18.0), None];
+        merge_root_stats(&mut stats, vec![child(0, 9, 0.0), child(1, 1, 2.0), None]);
+        assert_eq!(best_mean_action(&stats).unwrap(), 1);
+        merge_root_stats(&mut stats, vec![None, None, child(2, 1, 5.0)]);
+        assert_eq!(stats[0].as_ref().unwrap().visit_count, 10);
+        assert_eq!(best_mean_action(&stats).unwrap(), 2);
+        assert_eq!(
+            best_mean_action(&[None, None]),
+            Err(RmctsError::NoLegalAction)
+        );
+    }
+
+    #[test]
+    fn test_collect_names_the_silent_planner() {
+        let (reply_tx, rx) = mpsc::channel();
+        let mut planners = Vec::new();
+        let mut txs = Vec::new();
+        for id in 0..2 {
+            let (tx, planner_rx) = mpsc::channel::<PlanMessage<SymbolLang, ()>>();
+            let reply_tx = reply_tx.clone();
+            planners.push(thread::spawn(move || {
+                // planner 1 exits without replying while planner 0 stays alive
+                if id == 0 {
+                    let _ = reply_tx.send((id, Ok((vec![None], 0))));
+                    let _ = planner_rx.recv();
+                }
+            }));
+            txs.push(tx);
+        }
+        drop(reply_tx);
+        let mut root_parallel = RootParallel {
+            peak_memory: vec![0; planners.len()],
+            planners,
+            txs,
+            rx,
+        };
+        assert_eq!(root_parallel.collect(&mut [None]), Err(crashed(1)));
+    }
+}
@@ -1,85 +1,279 @@
+use crate::action::Action;
+use crate::error::RmctsError;
+use crate::policy::{SelectionPolicy, Ucb1};
+use crate::reward::{IntoReward, RewardFn, RootCost};
+use crate::rollout::{RolloutAggregation, RolloutPolicy, Uniform, ValueEstimator};
 use crate::tree;
 #[allow(unused_imports)]
-use egg::{Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, RecExpr, Rewrite};
+use egg::{
+    Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, RecExpr, Report, Rewrite,
+    StopReason,
+};
+use std::sync::Arc;
+use std::time::Duration;
 
+#[derive(Debug, Clone)]
 pub struct MCTSArgs {
+    /// Number of simulations per planning step; 0 leaves planning bounded only by
+    /// `planning_time_limit`.
     pub budget: u32,
+    /// Wall-clock limit per planning step. Together with a non-zero `budget`,
+    /// planning stops at whichever is hit first.
+    pub planning_time_limit: Option<Duration>,
     pub max_sim_step: u32,
+    /// Deadline of each simulation; a simulation past it stops after its current egg
+    /// iteration and counts the return of the steps taken so far.
+    pub task_timeout: Option<Duration>,
+    /// Default policy of the rollouts, see [`crate::rollout`].
+    pub rollout_policy: Arc<dyn RolloutPolicy>,
+    /// Rollouts run in parallel from each expanded leaf; their returns are combined
+    /// by `rollout_aggregation` into one backed-up value.
+    pub rollouts_per_leaf: usize,
+    pub rollout_aggregation: RolloutAggregation,
+    /// Estimate of `V(s)` at the start of each simulation; `None` counts as 0.
+    pub value_estimator: Option<Arc<dyn ValueEstimator>>,
+    /// Weight of the rollout return against the `value_estimator`, in `[0, 1]`.
+    pub value_factor: f32,
     pub gamma: f32,
+    /// Tree policy scoring children during selection, see [`crate::policy`].
+    pub selection_policy: Arc<dyn SelectionPolicy>,
+    /// Probability that selection expands a non-root node that still has unvisited
+    /// actions instead of descending into an expanded child.
+    pub expansion_prob: f32,
     pub expansion_worker_num: usize,
     pub simulation_worker_num: usize,
+    /// Run both kinds of tasks on one pool of `expansion_worker_num +
+    /// simulation_worker_num` workers, expansions first. The pool never grows or
+    /// shrinks; only the split between expansions and simulations adapts to
+    /// whichever is the bottleneck.
+    pub unified_pool: bool,
+    /// Number of independent trees planning every step, each with its own seed and
+    /// worker pools; their root statistics are merged to pick the action. 1 plans
+    /// with a single tree.
+    pub root_parallel: usize,
     pub lp_extract: bool,
-    pub cost_threshold: usize,
+    /// Only expand, select and roll out rules that match somewhere in the e-graph and
+    /// did not saturate since it last changed; costs a search of every rule per step.
+    /// Off by default, which keeps every rule selectable as in plain MCTS-GEB.
+    pub action_mask: bool,
+    /// Action space of the search, see [`Action`]; `None` for one action per rule.
+    pub actions: Option<Vec<Action>>,
+    /// How the costs of several roots are combined.
+    pub root_cost: RootCost,
+    pub reward_fn: RewardFn,
+    pub cost_threshold: f64,
     pub iter_limit: usize,
+    /// Seed of every random choice of the search; `None` draws one from the OS.
+    pub seed: Option<u64>,
+    /// Apply worker results in the order their tasks were assigned rather than as
+    /// they complete, so a `seed` reproduces the same action trace. Slower, and
+    /// requires planning to be bounded by `budget` alone.
+    pub deterministic: bool,
+    /// Keep the subtree under the chosen action, with its statistics and
+    /// checkpoints, as the root of the next planning step. Off by default, as the
+    /// kept checkpoints stay in memory across steps.
+    pub reuse_tree: bool,
+    /// How checkpoints of expanded nodes hold their e-graph.
+    pub checkpoint_mode: CheckpointMode,
+    /// Keep at most this many e-graph snapshots in the tree; the least recently
+    /// used ones are dropped and their states replayed on demand.
+    pub max_snapshots: Option<usize>,
+    /// Approximate bytes the planner may hold in checkpoints and in-flight tasks;
+    /// over it, snapshots are evicted and new tasks wait for in-flight ones.
+    pub memory_limit: Option<usize>,
 
     pub node_limit: usize,
     pub time_limit: usize,
 }
 
+impl Default for MCTSArgs {
+    fn default() -> Self {
+        MCTSArgs {
+            // mcts
+            budget: 12,
+            planning_time_limit: None,
+            max_sim_step: 5,
+            task_timeout: None,
+            rollout_policy: Arc::new(Uniform),
+            rollouts_per_leaf: 1,
+            rollout_aggregation: RolloutAggregation::Mean,
+            value_estimator: None,
+            value_factor: 1.0,
+            gamma: 0.99,
+            selection_policy: Arc::new(Ucb1 { c: 2.0 }),
+            expansion_prob: 0.5,
+            expansion_worker_num: 1,
+            simulation_worker_num: 4,
+            unified_pool: false,
+            root_parallel: 1,
+            lp_extract: false,
+            action_mask: false,
+            actions: None,
+            root_cost: RootCost::Sum,
+            reward_fn: RewardFn::AbsoluteDelta,
+            cost_threshold: 1.0,
+            iter_limit: 30,
+            seed: None,
+            deterministic: false,
+            reuse_tree: false,
+            checkpoint_mode: CheckpointMode::Snapshot,
+            max_snapshots: None,
+            memory_limit: None,
+            // egg
+            node_limit: 10_000,
+            time_limit: 1,
+        }
+    }
+}
+
+impl MCTSArgs {
+    /// Reject combinations that would otherwise panic inside `Tree` or its workers.
+    pub fn validate(&self) -> Result<(), RmctsError> {
+        let invalid = |msg: &str| Err(RmctsError::InvalidConfig(msg.to_string()));
+        if self.budget == 0 && self.planning_time_limit.is_none() {
+            return invalid("budget 0 requires a planning_time_limit");
+        }
+        if self.planning_time_limit == Some(Duration::ZERO) {
+            return invalid("planning_time_limit must be positive");
+        }
+        if self.root_parallel == 0 {
+            return invalid("root_parallel must be at least 1");
+        }
+        if self.rollouts_per_leaf == 0 {
+            return invalid("rollouts_per_leaf must be at least 1");
+        }
+        if self.task_timeout == Some(Duration::ZERO) {
+            return invalid("task_timeout must be positive");
+        }
+        if self.deterministic && self.planning_time_limit.is_some() {
+            return invalid("deterministic planning cannot use a planning_time_limit");
+        }
+        if self.deterministic && self.task_timeout.is_some() {
+            return invalid("deterministic planning cannot use a task_timeout");
+        }
+        if !(self.gamma > 0.0 && self.gamma <= 1.0) {
+            return invalid("gamma must be in (0, 1]");
+        }
+        if !(0.0..=1.0).contains(&self.value_factor) {
+            return invalid("value_factor must be in [0, 1]");
+        }
+        if !(0.0..=1.0).contains(&self.expansion_prob) {
+            return invalid("expansion_prob must be in [0, 1]");
+        }
+        if self.expansion_worker_num == 0 {
+            return invalid("expansion_worker_num must be at least 1");
+        }
+        if self.simulation_worker_num == 0 {
+            return invalid("simulation_worker_num must be at least 1");
+        }
+        if self.max_snapshots == Some(0) {
+            return invalid("max_snapshots must be at least 1");
+        }
+        if self.memory_limit == Some(0) {
+            return invalid("memory_limit must be positive");
+        }
+        if self.iter_limit == 0 {
+            return invalid("iter_limit must be at least 1");
+        }
+        if self.node_limit == 0 {
+            return invalid("node_limit must be at least 1");
+        }
+        if self.time_limit == 0 {
+            return invalid("time_limit must be at least 1s");
+        }
+        Ok(())
+    }
+}
+
+/// How a checkpoint stores the state of the e-graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckpointMode {
+    /// An immutable snapshot shared by the tree and its tasks; restoring clones it.
+    #[default]
+    Snapshot,
+    /// Only the actions taken since the initial e-graph; restoring replays them,
+    /// trading egg runs for memory.
+    Replay,
+}
+
+/// Why the outer environment loop of [`run_mcts`] stopped.
+#[derive(Debug, Clone)]
+pub enum MctsStopReason {
+    /// The environment reached a terminal state, e.g. every rule saturated or egg
+    /// hit its node/time limit; carries egg's stop reason of the last step.
+    Done(StopReason),
+    /// The extracted cost dropped below `cost_threshold`.
+    CostThreshold,
+    /// `iter_limit` environment steps were taken.
+    IterLimit,
+}
+
+/// Statistics of one environment step (one planning call followed by one rewrite).
+#[derive(Debug, Clone)]
+pub struct IterationStats {
+    pub iter: usize,
+    pub action: usize,
+    pub action_name: String,
+    pub planning_time: Duration,
+    pub reward: f32,
+    pub episode_reward: f32,
+    pub best_cost: f64,
+    pub report: Report,
+}
+
+/// Outcome of a full MCTS run.
+pub struct MctsResult<L, N>
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    /// The e-graph after applying the chosen action sequence.
+    pub egraph: EGraph<L, N>,
+    /// Best expression extracted from `egraph` for each root, in the order of the
+    /// roots passed to [`run_mcts`].
+    pub best_exprs: Vec<RecExpr<L>>,
+    /// Costs on the [`IntoReward`] scale.
+    pub base_cost: f64,
+    pub final_cost: f64,
+    /// The chosen action sequence as `(action index, action name)`; see [`Action`].
+    pub actions: Vec<(usize, String)>,
+    pub iterations: Vec<IterationStats>,
+    pub total_planning_time: Duration,
+    pub stop_reason: MctsStopReason,
+    /// Peak approximate bytes held by checkpoints and in-flight tasks, see
+    /// [`MCTSArgs::memory_limit`].
+    pub peak_memory: usize,
+}
+
 pub fn run_mcts<L, N, CF>(
     egraph: EGraph<L, N>,
-    id: Id,
+    roots: &[Id],
     rules: Vec<Rewrite<L, N>>,
     cf: CF,
     args: Option<MCTSArgs>,
-) -> EGraph<L, N>
+) -> Result<MctsResult<L, N>, RmctsError>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
-    // Args
-    // mcts
-    let mut budget = 12;
-    let mut max_sim_step = 5;
-    let mut gamma = 0.99;
-    let mut expansion_worker_num = 1;
-    let mut simulation_worker_num = 4;
-    let mut lp_extract = false;
-    let mut cost_threshold = 1;
-    let mut iter_limit = 30;
-    // let verbose = false;
-    // egg
-    let mut node_limit = 10_000;
-    let mut time_limit = 1;
-
-    // overwrite params if possible
-    match args {
-        None => (),
-        Some(args) => {
-            budget = args.budget;
-            max_sim_step = args.max_sim_step;
-            gamma = args.gamma;
-            expansion_worker_num = args.expansion_worker_num;
-            simulation_worker_num = args.simulation_worker_num;
-            lp_extract = args.lp_extract;
-            cost_threshold = args.cost_threshold;
-            iter_limit = args.iter_limit;
-
-            node_limit = args.node_limit;
-            time_limit = args.time_limit;
-        }
+    let args = args.unwrap_or_default();
+    args.validate()?;
+    if roots.is_empty() {
+        return Err(RmctsError::InvalidConfig("no roots given".to_string()));
     }
+    let cost_threshold = args.cost_threshold;
+    let iter_limit = args.iter_limit;
 
     // Run
-    let mut mcts = tree::Tree::new(
-        // mcts
-        budget,
-        max_sim_step,
-        gamma,
-        expansion_worker_num,
-        simulation_worker_num,
-        // egg
-        egraph.clone(),
-        id.clone(),
-        rules.clone(),
-        cf,
-        lp_extract,
-        node_limit,
-        time_limit,
-    );
-    mcts.run_loop(egraph, id, rules.clone(), cost_threshold, iter_limit)
+    let mut mcts = tree::Tree::new(&args, egraph.clone(), roots, rules.clone(), cf);
+    mcts.run_loop(egraph, roots, rules, cost_threshold, iter_limit)
 }
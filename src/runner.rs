@@ -0,0 +1,307 @@
+use crate::action::Action;
+use crate::error::RmctsError;
+use crate::policy::SelectionPolicy;
+use crate::reward::{IntoReward, RewardFn, RootCost};
+use crate::rollout::{RolloutAggregation, RolloutPolicy, ValueEstimator};
+use crate::run::{run_mcts, CheckpointMode, MCTSArgs, MctsResult};
+
+#[allow(unused_imports)]
+use egg::{Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, RecExpr, Rewrite};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Builder for an MCTS run, configured the same way as [`egg::Runner`].
+pub struct MctsRunner<L, N, CF>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    /// The e-graph to optimise.
+    pub egraph: EGraph<L, N>,
+    /// The roots of the expressions added by [`MctsRunner::with_expr`].
+    pub roots: Vec<Id>,
+    /// The search parameters, see the `with_*` methods.
+    pub args: MCTSArgs,
+    cf: CF,
+}
+
+impl<L, N, CF> Default for MctsRunner<L, N, CF>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static + Default,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    fn default() -> Self {
+        MctsRunner::new(CF::default())
+    }
+}
+
+impl<L, N, CF> MctsRunner<L, N, CF>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    /// Create a runner with an empty e-graph, default [`MCTSArgs`] and the given
+    /// cost function.
+    pub fn new(cf: CF) -> Self {
+        MctsRunner {
+            egraph: EGraph::default(),
+            roots: vec![],
+            args: MCTSArgs::default(),
+            cf,
+        }
+    }
+
+    /// Number of simulations per planning step, 0 for no limit on the count.
+    pub fn with_budget(mut self, budget: u32) -> Self {
+        self.args.budget = budget;
+        self
+    }
+
+    /// Wall-clock limit of each planning step.
+    pub fn with_planning_time_limit(mut self, planning_time_limit: Duration) -> Self {
+        self.args.planning_time_limit = Some(planning_time_limit);
+        self
+    }
+
+    /// Maximum number of steps of a single rollout.
+    pub fn with_max_sim_step(mut self, max_sim_step: u32) -> Self {
+        self.args.max_sim_step = max_sim_step;
+        self
+    }
+
+    /// Stop each simulation after `task_timeout`, keeping its partial return.
+    pub fn with_task_timeout(mut self, task_timeout: Duration) -> Self {
+        self.args.task_timeout = Some(task_timeout);
+        self
+    }
+
+    /// Default policy of the rollouts.
+    pub fn with_rollout_policy(mut self, rollout_policy: Arc<dyn RolloutPolicy>) -> Self {
+        self.args.rollout_policy = rollout_policy;
+        self
+    }
+
+    /// Run `rollouts_per_leaf` rollouts from every expanded leaf and back up their
+    /// returns combined by `aggregation`.
+    pub fn with_rollouts_per_leaf(
+        mut self,
+        rollouts_per_leaf: usize,
+        aggregation: RolloutAggregation,
+    ) -> Self {
+        self.args.rollouts_per_leaf = rollouts_per_leaf;
+        self.args.rollout_aggregation = aggregation;
+        self
+    }
+
+    /// Blend every simulation return with `value_estimator`, weighting the rollout by
+    /// `value_factor`.
+    pub fn with_value_estimator(
+        mut self,
+        value_estimator: Arc<dyn ValueEstimator>,
+        value_factor: f32,
+    ) -> Self {
+        self.args.value_estimator = Some(value_estimator);
+        self.args.value_factor = value_factor;
+        self
+    }
+
+    /// Discount factor of rewards, in `(0, 1]`.
+    pub fn with_gamma(mut self, gamma: f32) -> Self {
+        self.args.gamma = gamma;
+        self
+    }
+
+    /// Tree policy scoring children during selection.
+    pub fn with_selection_policy(mut self, selection_policy: Arc<dyn SelectionPolicy>) -> Self {
+        self.args.selection_policy = selection_policy;
+        self
+    }
+
+    /// Probability of expanding a non-root node that still has unvisited actions.
+    pub fn with_expansion_prob(mut self, expansion_prob: f32) -> Self {
+        self.args.expansion_prob = expansion_prob;
+        self
+    }
+
+    /// Number of expansion worker threads.
+    pub fn with_exp_workers(mut self, expansion_worker_num: usize) -> Self {
+        self.args.expansion_worker_num = expansion_worker_num;
+        self
+    }
+
+    /// Number of simulation worker threads.
+    pub fn with_sim_workers(mut self, simulation_worker_num: usize) -> Self {
+        self.args.simulation_worker_num = simulation_worker_num;
+        self
+    }
+
+    /// Share one pool of workers between expansions and simulations.
+    pub fn with_unified_pool(mut self, unified_pool: bool) -> Self {
+        self.args.unified_pool = unified_pool;
+        self
+    }
+
+    /// Plan every step with `trees` independent trees and merge their root statistics.
+    pub fn with_root_parallel(mut self, trees: usize) -> Self {
+        self.args.root_parallel = trees;
+        self
+    }
+
+    /// Extract with [`egg::LpExtractor`] instead of [`egg::Extractor`].
+    pub fn with_lp_extract(mut self, lp_extract: bool) -> Self {
+        self.args.lp_extract = lp_extract;
+        self
+    }
+
+    /// Only consider rules that match somewhere in the e-graph.
+    pub fn with_action_mask(mut self, action_mask: bool) -> Self {
+        self.args.action_mask = action_mask;
+        self
+    }
+
+    /// Plan over macro actions instead of one action per rule.
+    pub fn with_actions(mut self, actions: Vec<Action>) -> Self {
+        self.args.actions = Some(actions);
+        self
+    }
+
+    /// How the costs of several roots are combined.
+    pub fn with_root_cost(mut self, root_cost: RootCost) -> Self {
+        self.args.root_cost = root_cost;
+        self
+    }
+
+    /// Reward shaping of each rewrite step.
+    pub fn with_reward_fn(mut self, reward_fn: RewardFn) -> Self {
+        self.args.reward_fn = reward_fn;
+        self
+    }
+
+    /// Stop once the extracted cost drops below this threshold.
+    pub fn with_cost_threshold(mut self, cost_threshold: f64) -> Self {
+        self.args.cost_threshold = cost_threshold;
+        self
+    }
+
+    /// Maximum number of environment steps, i.e. rewrites applied to the e-graph.
+    pub fn with_iter_limit(mut self, iter_limit: usize) -> Self {
+        self.args.iter_limit = iter_limit;
+        self
+    }
+
+    /// Node limit of each egg run.
+    pub fn with_node_limit(mut self, node_limit: usize) -> Self {
+        self.args.node_limit = node_limit;
+        self
+    }
+
+    /// Time limit of each egg run, in seconds.
+    pub fn with_time_limit(mut self, time_limit: usize) -> Self {
+        self.args.time_limit = time_limit;
+        self
+    }
+
+    /// Seed of every random choice of the search.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.args.seed = Some(seed);
+        self
+    }
+
+    /// Apply worker results in task order, so a seeded run is reproducible.
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.args.deterministic = deterministic;
+        self
+    }
+
+    /// Keep the subtree under the chosen action for the next planning step.
+    pub fn with_reuse_tree(mut self, reuse_tree: bool) -> Self {
+        self.args.reuse_tree = reuse_tree;
+        self
+    }
+
+    /// Store checkpoints as shared snapshots or as replayed action histories.
+    pub fn with_checkpoint_mode(mut self, checkpoint_mode: CheckpointMode) -> Self {
+        self.args.checkpoint_mode = checkpoint_mode;
+        self
+    }
+
+    /// Keep at most `max_snapshots` e-graph snapshots, replaying evicted states.
+    pub fn with_max_snapshots(mut self, max_snapshots: usize) -> Self {
+        self.args.max_snapshots = Some(max_snapshots);
+        self
+    }
+
+    /// Bound the approximate bytes held by checkpoints and in-flight tasks.
+    pub fn with_memory_limit(mut self, memory_limit: usize) -> Self {
+        self.args.memory_limit = Some(memory_limit);
+        self
+    }
+
+    /// Replace all search parameters at once.
+    pub fn with_args(mut self, args: MCTSArgs) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Add an expression to the e-graph and register its root.
+    pub fn with_expr(mut self, expr: &RecExpr<L>) -> Self {
+        let id = self.egraph.add_expr(expr);
+        self.roots.push(id);
+        self
+    }
+
+    /// Replace the e-graph. Roots must be registered with [`MctsRunner::with_root`].
+    pub fn with_egraph(mut self, egraph: EGraph<L, N>) -> Self {
+        self.egraph = egraph;
+        self
+    }
+
+    /// Register an existing e-class of the e-graph as a root.
+    pub fn with_root(mut self, root: Id) -> Self {
+        self.roots.push(root);
+        self
+    }
+
+    /// Validate the configuration and run the search with the given rules.
+    pub fn run(self, rules: &[Rewrite<L, N>]) -> Result<MctsResult<L, N>, RmctsError> {
+        if rules.is_empty() {
+            return Err(RmctsError::InvalidConfig("no rules given".to_string()));
+        }
+        let mut egraph = self.egraph;
+        egraph.rebuild();
+        run_mcts(
+            egraph,
+            &self.roots,
+            rules.to_vec(),
+            self.cf,
+            Some(self.args),
+        )
+    }
+}
@@ -0,0 +1,19 @@
+//! Fixtures shared by the unit tests.
+use egg::{rewrite, EGraph, Id, Rewrite, SymbolLang};
+
+pub use crate::domains::simple::AstSize;
+
+pub fn make_rules() -> Vec<Rewrite<SymbolLang, ()>> {
+    vec![
+        rewrite!("commute-add"; "(+ ?a ?b)" => "(+ ?b ?a)"),
+        rewrite!("add-0"; "(+ ?a 0)" => "?a"),
+        rewrite!("mul-1"; "(* ?a 1)" => "?a"),
+    ]
+}
+
+pub fn make_egraph(expr: &str) -> (EGraph<SymbolLang, ()>, Id) {
+    let mut egraph = EGraph::default();
+    let root = egraph.add_expr(&expr.parse().unwrap());
+    egraph.rebuild();
+    (egraph, root)
+}
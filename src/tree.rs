@@ -1,31 +1,43 @@
+use crate::ckpt_store::{egraph_bytes, CkptStore};
 use crate::eg_env::{Ckpt, EgraphEnv};
+use crate::error::RmctsError;
 // use crate::env::Env;
-use crate::node::{Node, NodeStub};
+use crate::node::Node;
+use crate::policy::ChildStats;
 use crate::pool_manager;
+use crate::reward::IntoReward;
+use crate::root_parallel::{best_mean_action, RootParallel};
+use crate::run::{IterationStats, MCTSArgs, MctsResult, MctsStopReason};
 use crate::workers::Reply;
 
 #[allow(unused_imports)]
 use egg::{Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, RecExpr, Rewrite};
-use rand::Rng;
+use log::{debug, info};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::rc::Rc;
-use std::thread;
 use std::time::{Duration, Instant};
 // use log::info;
 
 pub struct ExpTask<L, N>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
 {
     // pub checkpoint_data: Vec<usize>,
     pub checkpoint_data: Ckpt<L, N>,
-    pub shallow_copy_node: NodeStub,
+    pub action: usize,
     d1: PhantomData<L>,
     d2: PhantomData<N>,
 }
@@ -33,157 +45,216 @@ where
 #[derive(Clone)]
 pub struct SimTask<L, N>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
 {
     pub checkpoint_data: Ckpt<L, N>,
     pub action: usize,
     pub saving_idx: u32,
     pub action_applied: bool,
     pub child_saturated: bool,
+    /// Seed of the rollout, so its result does not depend on which worker runs it.
+    pub seed: u64,
     d1: PhantomData<L>,
     d2: PhantomData<N>,
 }
 
+impl<L, N> SimTask<L, N>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+{
+    /// A rollout from `checkpoint_data`, the state reached by expanding `action`.
+    pub fn new(
+        checkpoint_data: Ckpt<L, N>,
+        action: usize,
+        saving_idx: u32,
+        child_saturated: bool,
+        seed: u64,
+    ) -> Self {
+        SimTask {
+            checkpoint_data,
+            action,
+            saving_idx,
+            action_applied: true,
+            child_saturated,
+            seed,
+            d1: PhantomData,
+            d2: PhantomData,
+        }
+    }
+}
+
 pub struct Tree<L, N, CF>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
     // from param
     budget: u32,
+    planning_time_limit: Option<Duration>,
     gamma: f32,
 
     // data and concurrency
-    exp_pool: pool_manager::PoolManager<L, N, CF>,
-    sim_pool: pool_manager::PoolManager<L, N, CF>,
+    // workers of the expansion and the simulation tasks, indexed by `exp_pool` and
+    // `sim_pool`; both index the same pool if it is unified
+    pools: Vec<pool_manager::PoolManager<L, N, CF>>,
+    exp_pool: usize,
+    sim_pool: usize,
+    // the other trees of root-parallel MCTS
+    root_parallel: Option<RootParallel<L, N>>,
     // ckpts: HashMap<u32, Vec<usize>>,
-    ckpts: HashMap<u32, Ckpt<L, N>>,
+    ckpts: CkptStore<L, N>,
+    // approximate bytes of the e-graph each in-flight task's worker restores
+    inflight_bytes: HashMap<u32, usize>,
+    peak_memory: usize,
     cf: CF,
-    lp_extract: bool,
+    args: MCTSArgs,
+    rng: ChaCha8Rng,
 
     // for planning
     root_node: Rc<RefCell<Node>>,
+    // root of the next planning step, if the tree is reused
+    next_root: Option<Rc<RefCell<Node>>>,
     global_saving_idx: u32,
+    // task ids are never reused, as reused nodes keep their traverse history
+    global_task_idx: u32,
     simulation_count: u32,
+    timed_out_count: u32,
     expansion_tasks: HashMap<u32, ExpTask<L, N>>,
     expansion_nodes_copy: HashMap<u32, Rc<RefCell<Node>>>,
     simulation_tasks: HashMap<u32, SimTask<L, N>>,
     simulation_nodes_copy: HashMap<u32, Rc<RefCell<Node>>>,
+    // rollouts of each leaf sent to a worker, and the returns received so far
+    rollouts_scheduled: HashMap<u32, usize>,
+    rollout_returns: HashMap<u32, Vec<f32>>,
     pending_expansion_tasks: VecDeque<u32>,
     pending_simulation_tasks: VecDeque<u32>,
 
     d1: PhantomData<L>,
     d2: PhantomData<N>,
-
-    // egg
-    node_limit: usize,
-    time_limit: usize,
 }
 
 impl<L, N, CF> Tree<L, N, CF>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
 {
     pub fn new(
-        // mcts
-        budget: u32,
-        max_sim_step: u32,
-        gamma: f32,
-        expansion_worker_num: usize,
-        simulation_worker_num: usize,
+        args: &MCTSArgs,
         // egg
         egraph: EGraph<L, N>,
-        id: Id,
+        roots: &[Id],
         rules: Vec<Rewrite<L, N>>,
         cf: CF,
-        lp_extract: bool,
-        node_limit: usize,
-        time_limit: usize,
     ) -> Self {
-        assert_eq!(expansion_worker_num, 1); // more than 1 expansion may have problem
-        Tree {
-            budget: budget,
-            gamma: gamma,
-
-            exp_pool: pool_manager::PoolManager::new(
-                "expansion",
-                expansion_worker_num,
-                gamma,
-                max_sim_step,
-                false,
-                egraph.clone(),
-                id.clone(),
-                rules.clone(),
-                cf.clone(),
-                lp_extract,
-                node_limit,
-                time_limit,
-            ),
-            sim_pool: pool_manager::PoolManager::new(
-                "simulation",
-                simulation_worker_num,
-                gamma,
-                max_sim_step,
-                false,
+        let new_pool = |name, work_num| {
+            pool_manager::PoolManager::new(
+                name,
+                work_num,
+                args,
                 egraph.clone(),
-                id.clone(),
+                roots,
                 rules.clone(),
                 cf.clone(),
-                lp_extract,
-                node_limit,
-                time_limit,
-            ),
-            ckpts: HashMap::new(),
+            )
+        };
+        let (pools, sim_pool) = if args.unified_pool {
+            // any idle worker takes either kind of task
+            let work_num = args.expansion_worker_num + args.simulation_worker_num;
+            (vec![new_pool("unified", work_num)], 0)
+        } else {
+            (
+                vec![
+                    new_pool("expansion", args.expansion_worker_num),
+                    new_pool("simulation", args.simulation_worker_num),
+                ],
+                1,
+            )
+        };
+        Tree {
+            budget: args.budget,
+            planning_time_limit: args.planning_time_limit,
+            gamma: args.gamma,
+
+            pools,
+            exp_pool: 0,
+            sim_pool,
+            root_parallel: (args.root_parallel > 1)
+                .then(|| RootParallel::new(args, egraph.clone(), roots, rules.clone(), cf.clone())),
+            ckpts: CkptStore::new(args.max_snapshots),
+            inflight_bytes: HashMap::new(),
+            peak_memory: 0,
             cf: cf,
-            lp_extract: lp_extract,
+            args: args.clone(),
+            rng: match args.seed {
+                Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+                None => ChaCha8Rng::from_entropy(),
+            },
 
             root_node: Node::dummy(),
+            next_root: None,
             global_saving_idx: 0,
+            global_task_idx: 0,
             simulation_count: 0,
+            timed_out_count: 0,
             expansion_tasks: HashMap::new(),
             expansion_nodes_copy: HashMap::new(),
             simulation_tasks: HashMap::new(),
             simulation_nodes_copy: HashMap::new(),
+            rollouts_scheduled: HashMap::new(),
+            rollout_returns: HashMap::new(),
             pending_expansion_tasks: VecDeque::new(),
             pending_simulation_tasks: VecDeque::new(),
             d1: PhantomData,
             d2: PhantomData,
-            node_limit: node_limit,
-            time_limit: time_limit,
         }
     }
 
     pub fn run_loop(
         &mut self,
         egraph: EGraph<L, N>,
-        id: Id,
+        roots: &[Id],
         rules: Vec<Rewrite<L, N>>,
-        cost_threshold: usize,
+        cost_threshold: f64,
         iter_limit: usize,
-    ) -> EGraph<L, N> {
+    ) -> Result<MctsResult<L, N>, RmctsError> {
         // env
         // let mut env = Env::new(expr, rules, self.node_limit, self.time_limit);
-        let mut env = EgraphEnv::new(
-            egraph,
-            id,
-            rules,
-            self.cf.clone(),
-            self.lp_extract,
-            self.node_limit,
-            self.time_limit,
-        );
+        let mut env =
+            EgraphEnv::from_args(egraph, roots.to_vec(), rules, self.cf.clone(), &self.args)?;
         env.reset();
 
         // loop var
@@ -193,131 +264,294 @@ where
         let mut info;
         let mut iter = 0;
         let mut episode_reward = 0.0;
-        let mut total_planning_time = 0;
+        let mut total_planning_time = Duration::ZERO;
+        let mut actions = Vec::new();
+        let mut iterations = Vec::new();
+        let stop_reason;
 
         // env loop
         loop {
+            if !env.legal_actions().contains(&true) {
+                // e.g. no rule matches the input at all
+                stop_reason = MctsStopReason::Done(egg::StopReason::Saturated);
+                break;
+            }
             let planning_time = Instant::now();
-            let action = self.plan(&state, &env);
-            let planning_time = planning_time.elapsed().as_secs();
+            let action = match self.plan(&state, &env) {
+                Ok(action) => action,
+                Err(RmctsError::NoLegalAction) => {
+                    // no root child to choose from, so no rule can improve the e-graph
+                    stop_reason = MctsStopReason::Done(egg::StopReason::Saturated);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let planning_time = planning_time.elapsed();
             total_planning_time += planning_time;
 
-            (state, reward, done, info) = env.step(action);
+            (state, reward, done, info) = env.step(action)?;
+            self.advance_root(action);
 
             iter += 1;
             episode_reward += reward;
 
-            println!(
+            info!(
                 "Iter {}; planning time {}s; reward {}; episode_reward {}; best cost {}",
-                iter, planning_time, reward, episode_reward, info.best_cost
+                iter,
+                planning_time.as_secs(),
+                reward,
+                episode_reward,
+                info.best_cost
             );
-            println!("{}", info.report);
-            println!("************************");
-            if done || info.best_cost < cost_threshold || iter >= iter_limit {
+            debug!("{}", info.report);
+
+            let action_name = env.get_action_name(action);
+            actions.push((action, action_name.clone()));
+            iterations.push(IterationStats {
+                iter,
+                action,
+                action_name,
+                planning_time,
+                reward,
+                episode_reward,
+                best_cost: info.best_cost,
+                report: info.report.clone(),
+            });
+
+            if done {
+                stop_reason = MctsStopReason::Done(info.report.stop_reason);
+                break;
+            }
+            if info.best_cost < cost_threshold {
+                stop_reason = MctsStopReason::CostThreshold;
+                break;
+            }
+            if iter >= iter_limit {
+                stop_reason = MctsStopReason::IterLimit;
                 break;
             }
         }
-        println!(
-            "[RMCTS] Done:: base_cost {} -> cost {} with iter {} and time {}s",
-            env.base_cost, info.best_cost, iter, total_planning_time,
+        info!(
+            "[RMCTS] Done:: base_cost {} -> cost {} with iter {} and time {}s; peak memory {:.1}MB",
+            env.base_cost,
+            env.last_cost,
+            iter,
+            total_planning_time.as_secs(),
+            self.peak_memory() as f64 / 1e6,
         );
 
-        self.close();
-        env.egraph
+        self.close()?;
+        Ok(MctsResult {
+            best_exprs: env.get_best_exprs()?,
+            base_cost: env.base_cost,
+            final_cost: env.last_cost,
+            egraph: env.egraph,
+            actions,
+            iterations,
+            total_planning_time,
+            stop_reason,
+            peak_memory: self.peak_memory(),
+        })
     }
 
     // fn plan(&mut self, _state: &(), env: &Env<L, N>) -> usize {
-    fn plan(&mut self, _state: &(), env: &EgraphEnv<L, N, CF>) -> usize {
+    fn plan(&mut self, _state: &(), env: &EgraphEnv<L, N, CF>) -> Result<usize, RmctsError> {
         // skip if action space is 1
         let action_n = env.get_action_space();
         if action_n == 1 {
-            return 0;
+            self.root_node = Node::dummy();
+            return Ok(0);
+        }
+
+        if let Some(root_parallel) = &self.root_parallel {
+            root_parallel.plan(&env.snapshot())?;
         }
+        self.search(env)?;
+
+        // final action
+        match &mut self.root_parallel {
+            None => self
+                .root_node
+                .borrow()
+                .select_uct_action(true, self.args.selection_policy.as_ref()),
+            Some(root_parallel) => {
+                let mut stats = self.root_node.borrow().children_stats();
+                root_parallel.collect(&mut stats)?;
+                best_mean_action(&stats)
+            }
+        }
+    }
+
+    /// Search from the state of `env` and return the statistics of the root's
+    /// children.
+    pub fn search(
+        &mut self,
+        env: &EgraphEnv<L, N, CF>,
+    ) -> Result<Vec<Option<ChildStats>>, RmctsError> {
+        let action_n = env.get_action_space();
 
         // clear
-        self.global_saving_idx = 0;
         self.simulation_count = 0;
-        self.ckpts.clear();
+        self.timed_out_count = 0;
         self.expansion_tasks.clear();
         self.expansion_nodes_copy.clear();
         self.simulation_tasks.clear();
         self.simulation_nodes_copy.clear();
+        self.rollouts_scheduled.clear();
+        self.rollout_returns.clear();
         self.pending_expansion_tasks.clear();
         self.pending_simulation_tasks.clear();
-        self.exp_pool.wait_until_all_idle();
-        self.sim_pool.wait_until_all_idle();
+        for pool in self.pools.iter_mut() {
+            pool.wait_until_all_idle()?;
+        }
+        self.inflight_bytes.clear();
 
-        // build current state
-        self.ckpts.insert(self.global_saving_idx, env.checkpoint());
-        self.root_node = Node::new(action_n, self.global_saving_idx, self.gamma, true, None);
-        self.global_saving_idx += 1;
+        // build current state, or continue from the subtree of the last action
+        match self.next_root.take() {
+            Some(root) => {
+                // the env state is authoritative over the expansion worker's copy
+                let checkpoint_idx = root.borrow().checkpoint_idx;
+                self.ckpts.insert(checkpoint_idx, env.checkpoint());
+                self.root_node = root;
+            }
+            None => {
+                self.ckpts.clear();
+                self.ckpts.insert(self.global_saving_idx, env.checkpoint());
+                self.root_node =
+                    Node::new(action_n, self.global_saving_idx, self.gamma, true, None);
+                self.root_node
+                    .borrow_mut()
+                    .set_legal_actions(env.legal_actions().to_vec());
+                self.global_saving_idx += 1;
+            }
+        }
+        self.account_memory();
 
-        // run main mcts
+        // run main mcts until the simulation count or the wall-clock budget is hit,
+        // a budget of 0 means the planning time limit alone bounds planning
+        let start = Instant::now();
         let mut depth = 0;
-        for sim_idx in 0..self.budget {
-            let d = self.simulate_single_step(sim_idx);
+        let mut sim_idx = 0;
+        let mut timed_out = false;
+        while self.budget == 0 || sim_idx < self.budget {
+            if let Some(limit) = self.planning_time_limit {
+                if start.elapsed() >= limit {
+                    timed_out = true;
+                    break;
+                }
+            }
+            let task_idx = self.global_task_idx;
+            self.global_task_idx += 1;
+            let d = self.simulate_single_step(task_idx)?;
             depth = std::cmp::max(depth, d);
+            sim_idx += 1;
+        }
+        if timed_out {
+            debug!("planning time limit hit after {} simulations", sim_idx);
+        }
+        if timed_out && !self.root_node.borrow().no_child_available() {
+            // out of time: finish in-flight tasks and drop those not yet started
+            self.abandon()?;
+        } else {
+            // nothing to choose from yet or within budget: complete every simulation
+            self.drain()?;
         }
 
         // clean up
-        println!(
-            "complete count {}/{} - max_depth {}",
-            self.simulation_count, self.budget, depth
+        debug!(
+            "complete count {}/{} - timed out {} - max_depth {} - {:.2}s",
+            self.simulation_count,
+            sim_idx,
+            self.timed_out_count,
+            depth,
+            start.elapsed().as_secs_f64()
         );
-        thread::sleep(Duration::from_secs(1));
 
-        // it is a bad idea to termiante a thread, perhaps just timeout a function in worker
-        // thread, as a way to handle stragger
-
-        // final action
-        self.root_node.borrow().select_uct_action(true)
+        let stats = self.root_node.borrow().children_stats();
+        Ok(stats)
     }
 
-    fn simulate_single_step(&mut self, sim_idx: u32) -> u32 {
+    fn simulate_single_step(&mut self, sim_idx: u32) -> Result<u32, RmctsError> {
         // Selection
         let mut curr_node: Rc<RefCell<Node>> = Rc::clone(&self.root_node);
         let mut curr_depth = 1;
-        let mut rng = rand::thread_rng();
         let need_expansion;
 
         loop {
-            let rand = rng.gen_range(0.0..1.0);
+            let rand = self.rng.gen_range(0.0..1.0);
+            let can_widen = curr_node
+                .borrow()
+                .can_widen(self.args.selection_policy.as_ref());
             if (curr_node.borrow().no_child_available())
-                || (curr_node.borrow().is_head && (!curr_node.borrow().all_child_visited()))
-                || ((!curr_node.borrow().is_head && !curr_node.borrow().all_child_visited())
-                    && rand < 0.5)
+                || (can_widen
+                    && curr_node.borrow().is_head
+                    && (!curr_node.borrow().all_child_visited()))
+                || (can_widen
+                    && (!curr_node.borrow().is_head && !curr_node.borrow().all_child_visited())
+                    && rand < self.args.expansion_prob)
             {
                 // If no child node has been updated, we have to expand anyway.
-                // Or if root node is not fully visited.
-                // Or if non-root node is not fully visited and {with prob 1/2}.
-
-                let cloned_curr_node = curr_node.borrow().shallow_clone();
-                let checkpoint_data = self
-                    .ckpts
-                    .get(&curr_node.borrow().checkpoint_idx)
-                    .unwrap()
-                    .clone();
-                // println!("{:?}", curr_node.children);
-
-                // Record the task
-                self.expansion_tasks.insert(
-                    sim_idx,
-                    ExpTask {
-                        checkpoint_data: checkpoint_data,
-                        shallow_copy_node: cloned_curr_node,
-                        d1: PhantomData,
-                        d2: PhantomData,
-                    },
-                );
-                self.expansion_nodes_copy
-                    .insert(sim_idx, Rc::clone(&curr_node));
-                self.pending_expansion_tasks.push_back(sim_idx);
+                // Or if the selection policy allows another child and
+                // root node is not fully visited,
+                // or non-root node is not fully visited and {with prob expansion_prob}.
 
-                need_expansion = true;
-                break;
+                // The expansion action is reserved here rather than in the worker, so
+                // concurrent expansions of the same node never pick the same action.
+                let expand_action = curr_node
+                    .borrow()
+                    .shallow_clone()
+                    .select_expansion_action(&mut self.rng);
+                if let Some(expand_action) = expand_action {
+                    curr_node.borrow_mut().mark_expanding(expand_action);
+                    let checkpoint_data = self
+                        .ckpts
+                        .get(curr_node.borrow().checkpoint_idx)
+                        .unwrap()
+                        .clone();
+
+                    // Record the task
+                    self.expansion_tasks.insert(
+                        sim_idx,
+                        ExpTask {
+                            checkpoint_data: checkpoint_data,
+                            action: expand_action,
+                            d1: PhantomData,
+                            d2: PhantomData,
+                        },
+                    );
+                    self.expansion_nodes_copy
+                        .insert(sim_idx, Rc::clone(&curr_node));
+                    self.pending_expansion_tasks.push_back(sim_idx);
+
+                    need_expansion = true;
+                    break;
+                }
+                // Every unexpanded action is being expanded by another task; descend
+                // through the expanded children if possible.
+                if curr_node.borrow().no_child_available() {
+                    if curr_node.borrow().is_expanding() && self.wait_for_task()? {
+                        // one of those tasks may have completed: select again from the root
+                        self.forget_task(Rc::clone(&curr_node), sim_idx);
+                        curr_node = Rc::clone(&self.root_node);
+                        curr_depth = 1;
+                        continue;
+                    }
+                    if curr_node.borrow().is_head {
+                        // every root child is saturated, nothing is left to simulate
+                        return Ok(curr_depth);
+                    }
+                    // every child is saturated: back up from the parent as if this
+                    // node were terminal
+                    let parent = Rc::clone(curr_node.borrow().parent.as_ref().unwrap());
+                    curr_node = parent;
+                    need_expansion = false;
+                    break;
+                }
             }
 
-            let action = curr_node.borrow().select_uct_action(false);
+            let action = curr_node
+                .borrow()
+                .select_uct_action(false, self.args.selection_policy.as_ref())?;
             let reward = curr_node.borrow().rewards[action].clone();
             curr_node
                 .borrow_mut()
@@ -340,75 +574,10 @@ where
 
         // Expansion
         if need_expansion {
-            // schedule
-            while !self.pending_expansion_tasks.is_empty() && self.exp_pool.has_idle_server() {
-                let task_idx = self.pending_expansion_tasks.pop_front().unwrap();
-                let exp_task = self.expansion_tasks.remove(&task_idx).unwrap(); // remove get
-                                                                                // ownership
-                self.exp_pool
-                    .assign_expansion_task(exp_task, self.global_saving_idx, task_idx);
-                self.global_saving_idx += 1;
-            }
+            self.schedule_expansions()?;
             // update
-            if self.exp_pool.occupancy() > 0.99 {
-                let reply = self.exp_pool.get_complete_task();
-                if let Reply::DoneExpansion(
-                    expand_action,
-                    _next_state,
-                    reward,
-                    done,
-                    child_saturated,
-                    new_checkpoint_data,
-                    saving_idx,
-                    task_idx,
-                ) = reply
-                {
-                    let curr_node_copy = self.expansion_nodes_copy.remove(&task_idx).unwrap();
-                    curr_node_copy
-                        .borrow_mut()
-                        .update_history(task_idx, expand_action, reward);
-                    curr_node_copy.borrow_mut().dones[expand_action] = done;
-                    curr_node_copy.borrow_mut().rewards[expand_action] = reward;
-
-                    if done {
-                        // If this expansion result in a terminal node,
-                        // perform update directly (simulation is not needed)
-                        assert!(new_checkpoint_data.is_none());
-                        curr_node_copy.borrow_mut().add_child(
-                            expand_action,
-                            saving_idx,
-                            self.gamma,
-                            child_saturated,
-                            Rc::clone(&curr_node_copy),
-                        );
-                        self.incomplete_update(Rc::clone(&curr_node_copy), task_idx);
-                        self.complete_update(Rc::clone(&curr_node_copy), task_idx, 0.0);
-                        self.simulation_count += 1;
-                    } else {
-                        // ELSE add_child will be done after simulation!
-                        // Add task to pending simulation
-                        assert!(new_checkpoint_data.is_some());
-                        let new_checkpoint_data = new_checkpoint_data.unwrap();
-                        self.ckpts.insert(saving_idx, new_checkpoint_data.clone());
-                        self.simulation_tasks.insert(
-                            task_idx,
-                            SimTask {
-                                checkpoint_data: new_checkpoint_data,
-                                action: expand_action,
-                                saving_idx: saving_idx,
-                                action_applied: true,
-                                child_saturated: child_saturated,
-                                d1: PhantomData,
-                                d2: PhantomData,
-                            },
-                        );
-                        self.simulation_nodes_copy
-                            .insert(task_idx, Rc::clone(&curr_node_copy));
-                        self.pending_simulation_tasks.push_back(task_idx)
-                    }
-                } else {
-                    panic!("DoneExpansion destructure fails");
-                }
+            if self.pools[self.exp_pool].occupancy() > 0.99 {
+                self.update(self.exp_pool)?;
             }
         } else {
             // no need expansion
@@ -419,42 +588,317 @@ where
         }
 
         // Simulation
-        // schedule
-        while !self.pending_simulation_tasks.is_empty() && self.sim_pool.has_idle_server() {
-            // pop a task
-            let task_idx = self.pending_simulation_tasks.pop_front().unwrap();
-            let sim_task = self.simulation_tasks.get(&task_idx).unwrap().clone();
-            let curr_node_copy = Rc::clone(self.simulation_nodes_copy.get(&task_idx).unwrap());
-            // schedule
-            self.sim_pool.assign_simulation_task(sim_task, task_idx);
-            // incomplete update
-            self.incomplete_update(Rc::clone(&curr_node_copy), task_idx);
-        }
+        self.schedule_simulations()?;
         // update
-        while self.sim_pool.occupancy() > 0.5
-            || (self.budget == sim_idx + 1 && self.simulation_count != self.budget)
+        while self.pools[self.sim_pool].occupancy() > 0.5 {
+            self.update(self.sim_pool)?;
+        }
+        Ok(curr_depth)
+    }
+
+    /// Wait for every expansion and simulation of this planning step to complete.
+    fn drain(&mut self) -> Result<(), RmctsError> {
+        loop {
+            self.schedule_expansions()?;
+            if self.pools[self.exp_pool].occupancy() > 0.0 {
+                self.update(self.exp_pool)?;
+            }
+            self.schedule_simulations()?;
+            if self.pools[self.sim_pool].occupancy() > 0.0 {
+                self.update(self.sim_pool)?;
+            }
+            if self.pending_expansion_tasks.is_empty()
+                && self.pending_simulation_tasks.is_empty()
+                && self.pools.iter_mut().all(|pool| pool.occupancy() == 0.0)
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Apply the results of in-flight tasks and drop every task not yet sent to a
+    /// worker, releasing its reserved action, so no node is left with a visit that
+    /// never completes.
+    fn abandon(&mut self) -> Result<(), RmctsError> {
+        // in-flight simulations reply early with their partial return
+        self.pools[self.sim_pool].cancel_all();
+        for pool in 0..self.pools.len() {
+            while self.pools[pool].occupancy() > 0.0 {
+                self.update(pool)?;
+            }
+        }
+        let dropped: Vec<_> = self.expansion_nodes_copy.drain().collect();
+        for (task_idx, node) in dropped {
+            let exp_task = self.expansion_tasks.remove(&task_idx).unwrap();
+            node.borrow_mut().unmark_expanding(exp_task.action);
+            self.forget_task(node, task_idx);
+        }
+        // leaves with some rollouts done back up the returns they have
+        let partial: Vec<u32> = self.rollout_returns.keys().copied().collect();
+        for task_idx in partial {
+            self.complete_simulation(task_idx)?;
+        }
+        let dropped: Vec<_> = self.simulation_nodes_copy.drain().collect();
+        for (task_idx, node) in dropped {
+            let sim_task = self.simulation_tasks.remove(&task_idx).unwrap();
+            node.borrow_mut().unmark_expanding(sim_task.action);
+            self.forget_task(node, task_idx);
+            // the leaf is never added, so neither is its checkpoint needed
+            self.ckpts.remove(sim_task.saving_idx);
+        }
+        self.pending_expansion_tasks.clear();
+        self.pending_simulation_tasks.clear();
+        Ok(())
+    }
+
+    /// Make the child under `action` the root of the next planning step and drop
+    /// the checkpoints outside its subtree; without tree reuse, or if the child was
+    /// never expanded, the next step starts from a fresh root.
+    pub fn advance_root(&mut self, action: usize) {
+        if let Some(root_parallel) = &self.root_parallel {
+            root_parallel.advance_root(action);
+        }
+        let child = self
+            .root_node
+            .borrow()
+            .children
+            .get(action)
+            .cloned()
+            .flatten();
+        self.root_node = Node::dummy();
+        self.next_root = None;
+        let child = match child {
+            Some(child) if self.args.reuse_tree => child,
+            _ => return,
+        };
+        {
+            let mut child = child.borrow_mut();
+            child.is_head = true;
+            child.parent = None;
+        }
+
+        let mut keep = std::collections::HashSet::new();
+        let mut stack = vec![Rc::clone(&child)];
+        while let Some(node) = stack.pop() {
+            let node = node.borrow();
+            keep.insert(node.checkpoint_idx);
+            stack.extend(node.children.iter().flatten().cloned());
+        }
+        self.ckpts.retain(|idx| keep.contains(&idx));
+        self.next_root = Some(child);
+    }
+
+    /// Approximate bytes held by the checkpoints and the in-flight tasks.
+    fn memory_usage(&self) -> usize {
+        self.ckpts.bytes() + self.inflight_bytes.values().sum::<usize>()
+    }
+
+    /// Evict snapshots to stay within `memory_limit` and record the peak usage.
+    fn account_memory(&mut self) {
+        if let Some(limit) = self.args.memory_limit {
+            let inflight: usize = self.inflight_bytes.values().sum();
+            self.ckpts.shrink_to(limit.saturating_sub(inflight));
+        }
+        self.peak_memory = std::cmp::max(self.peak_memory, self.memory_usage());
+    }
+
+    /// Whether a new task holding `bytes` fits within `memory_limit` next to the
+    /// in-flight ones. A task is always let through if no worker is busy, so
+    /// planning makes progress.
+    fn fits_memory_limit(&self, bytes: usize) -> bool {
+        match self.args.memory_limit {
+            Some(limit) => {
+                let inflight: usize = self.inflight_bytes.values().sum();
+                self.pools.iter().all(|pool| pool.is_idle()) || inflight + bytes <= limit
+            }
+            None => true,
+        }
+    }
+
+    fn task_bytes(checkpoint_data: &Ckpt<L, N>) -> usize {
+        checkpoint_data.egraph.as_deref().map_or(0, egraph_bytes)
+    }
+
+    fn schedule_expansions(&mut self) -> Result<(), RmctsError> {
+        while let Some(&task_idx) = self.pending_expansion_tasks.front() {
+            let bytes = Self::task_bytes(&self.expansion_tasks[&task_idx].checkpoint_data);
+            if !self.pools[self.exp_pool].has_idle_server() || !self.fits_memory_limit(bytes) {
+                break;
+            }
+            self.pending_expansion_tasks.pop_front();
+            let exp_task = self.expansion_tasks.remove(&task_idx).unwrap(); // remove get
+                                                                            // ownership
+            self.inflight_bytes.insert(task_idx, bytes);
+            self.account_memory();
+            self.pools[self.exp_pool].assign_expansion_task(
+                exp_task,
+                self.global_saving_idx,
+                task_idx,
+            )?;
+            self.global_saving_idx += 1;
+        }
+        Ok(())
+    }
+
+    /// Start whatever is pending and apply the next task completed by any pool;
+    /// `false` if no task is in flight.
+    fn wait_for_task(&mut self) -> Result<bool, RmctsError> {
+        self.schedule_expansions()?;
+        self.schedule_simulations()?;
+        match (0..self.pools.len()).find(|pool| self.pools[*pool].occupancy() > 0.0) {
+            Some(pool) => self.update(pool).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Apply the next task completed by a worker of `pool`.
+    fn update(&mut self, pool: usize) -> Result<(), RmctsError> {
+        match self.pools[pool].get_complete_task()? {
+            reply @ Reply::DoneExpansion(..) => self.update_expansion(reply),
+            reply => self.update_simulation(reply),
+        }
+    }
+
+    fn update_expansion(&mut self, reply: Reply<L, N>) -> Result<(), RmctsError> {
+        if let Reply::DoneExpansion(
+            expand_action,
+            _next_state,
+            reward,
+            done,
+            child_saturated,
+            new_checkpoint_data,
+            saving_idx,
+            task_idx,
+        ) = reply
         {
-            let reply = self.sim_pool.get_complete_task();
-            if let Reply::DoneSimulation(task_idx, accu_reward) = reply {
-                // fetch
-                let sim_task = self.simulation_tasks.remove(&task_idx).unwrap();
-                let curr_node_copy = self.simulation_nodes_copy.remove(&task_idx).unwrap();
-                assert!(sim_task.action_applied);
-                // add-child
+            self.inflight_bytes.remove(&task_idx);
+            let curr_node_copy = self.expansion_nodes_copy.remove(&task_idx).unwrap();
+            curr_node_copy
+                .borrow_mut()
+                .update_history(task_idx, expand_action, reward);
+            curr_node_copy.borrow_mut().dones[expand_action] = done;
+            curr_node_copy.borrow_mut().rewards[expand_action] = reward;
+
+            if done {
+                // If this expansion result in a terminal node,
+                // perform update directly (simulation is not needed)
+                assert!(new_checkpoint_data.is_none());
                 curr_node_copy.borrow_mut().add_child(
-                    sim_task.action,
-                    sim_task.saving_idx,
+                    expand_action,
+                    saving_idx,
                     self.gamma,
-                    sim_task.child_saturated,
+                    child_saturated,
+                    None,
                     Rc::clone(&curr_node_copy),
-                );
-                self.complete_update(Rc::clone(&curr_node_copy), task_idx, accu_reward);
+                )?;
+                self.incomplete_update(Rc::clone(&curr_node_copy), task_idx);
+                self.complete_update(Rc::clone(&curr_node_copy), task_idx, 0.0);
                 self.simulation_count += 1;
             } else {
-                panic!("DoneSimulation destructure fails");
+                // ELSE add_child will be done after simulation!
+                // Add task to pending simulation
+                assert!(new_checkpoint_data.is_some());
+                let new_checkpoint_data = new_checkpoint_data.unwrap();
+                self.ckpts.insert(saving_idx, new_checkpoint_data.clone());
+                self.account_memory();
+                self.simulation_tasks.insert(
+                    task_idx,
+                    SimTask::new(
+                        new_checkpoint_data,
+                        expand_action,
+                        saving_idx,
+                        child_saturated,
+                        self.rng.gen(),
+                    ),
+                );
+                self.simulation_nodes_copy
+                    .insert(task_idx, Rc::clone(&curr_node_copy));
+                for _ in 0..self.args.rollouts_per_leaf {
+                    self.pending_simulation_tasks.push_back(task_idx);
+                }
             }
+        } else {
+            panic!("DoneExpansion destructure fails");
         }
-        curr_depth
+        Ok(())
+    }
+
+    fn schedule_simulations(&mut self) -> Result<(), RmctsError> {
+        while let Some(&task_idx) = self.pending_simulation_tasks.front() {
+            let bytes = Self::task_bytes(&self.simulation_tasks[&task_idx].checkpoint_data);
+            if !self.pools[self.sim_pool].has_idle_server() || !self.fits_memory_limit(bytes) {
+                break;
+            }
+            // pop a task
+            self.pending_simulation_tasks.pop_front();
+            let mut sim_task = self.simulation_tasks.get(&task_idx).unwrap().clone();
+            let rollout = self.rollouts_scheduled.entry(task_idx).or_insert(0);
+            // every rollout of the leaf gets its own seed
+            sim_task.seed = sim_task.seed.wrapping_add(*rollout as u64);
+            *rollout += 1;
+            let first_rollout = *rollout == 1;
+            *self.inflight_bytes.entry(task_idx).or_insert(0) += bytes;
+            self.account_memory();
+            let curr_node_copy = Rc::clone(self.simulation_nodes_copy.get(&task_idx).unwrap());
+            // schedule
+            self.pools[self.sim_pool].assign_simulation_task(sim_task, task_idx)?;
+            // incomplete update, once per leaf
+            if first_rollout {
+                self.incomplete_update(Rc::clone(&curr_node_copy), task_idx);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_simulation(&mut self, reply: Reply<L, N>) -> Result<(), RmctsError> {
+        let (task_idx, accu_reward) = match reply {
+            Reply::DoneSimulation(task_idx, accu_reward) => (task_idx, accu_reward),
+            Reply::TimedOutSimulation(task_idx, accu_reward) => {
+                debug!("simulation {} timed out", task_idx);
+                self.timed_out_count += 1;
+                (task_idx, accu_reward)
+            }
+            _ => panic!("DoneSimulation destructure fails"),
+        };
+        let returns = self.rollout_returns.entry(task_idx).or_default();
+        returns.push(accu_reward);
+        if returns.len() < self.args.rollouts_per_leaf {
+            let sim_task = self.simulation_tasks.get(&task_idx).unwrap();
+            let bytes = Self::task_bytes(&sim_task.checkpoint_data);
+            if let Some(inflight) = self.inflight_bytes.get_mut(&task_idx) {
+                *inflight = inflight.saturating_sub(bytes);
+                if *inflight == 0 {
+                    self.inflight_bytes.remove(&task_idx);
+                }
+            }
+            return Ok(());
+        }
+        self.complete_simulation(task_idx)
+    }
+
+    /// Add the child simulated by task `task_idx` and back up the combined return
+    /// of its rollouts.
+    fn complete_simulation(&mut self, task_idx: u32) -> Result<(), RmctsError> {
+        let returns = self.rollout_returns.remove(&task_idx).unwrap();
+        let accu_reward = self.args.rollout_aggregation.aggregate(&returns);
+        // fetch
+        self.inflight_bytes.remove(&task_idx);
+        self.rollouts_scheduled.remove(&task_idx);
+        let sim_task = self.simulation_tasks.remove(&task_idx).unwrap();
+        let curr_node_copy = self.simulation_nodes_copy.remove(&task_idx).unwrap();
+        assert!(sim_task.action_applied);
+        // add-child
+        curr_node_copy.borrow_mut().add_child(
+            sim_task.action,
+            sim_task.saving_idx,
+            self.gamma,
+            sim_task.child_saturated,
+            Some(sim_task.checkpoint_data.legal_actions),
+            Rc::clone(&curr_node_copy),
+        )?;
+        self.complete_update(Rc::clone(&curr_node_copy), task_idx, accu_reward);
+        self.simulation_count += 1;
+        Ok(())
     }
 
     fn incomplete_update(&mut self, mut curr_node: Rc<RefCell<Node>>, idx: u32) {
@@ -466,6 +910,18 @@ where
         self.root_node.borrow_mut().update_incomplete(idx);
     }
 
+    /// Drop the history task `idx` left on the path from `curr_node` to the root.
+    fn forget_task(&self, mut curr_node: Rc<RefCell<Node>>, idx: u32) {
+        loop {
+            curr_node.borrow_mut().forget_history(idx);
+            let parent = match curr_node.borrow().parent.as_ref() {
+                Some(parent) if !curr_node.borrow().is_head => Rc::clone(parent),
+                _ => break,
+            };
+            curr_node = parent;
+        }
+    }
+
     fn complete_update(&mut self, mut curr_node: Rc<RefCell<Node>>, idx: u32, accu_reward: f32) {
         let mut rolling_accu_reward = accu_reward;
         while !curr_node.borrow().is_head {
@@ -480,15 +936,266 @@ where
             .update_complete(idx, rolling_accu_reward);
     }
 
-    fn close(&mut self) {
-        self.exp_pool.close();
-        self.sim_pool.close();
+    /// Peak approximate memory of the checkpoints and in-flight tasks, summed over
+    /// the trees if root-parallel.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+            + self
+                .root_parallel
+                .as_ref()
+                .map_or(0, |root_parallel| root_parallel.peak_memory())
+    }
+
+    pub fn close(&mut self) -> Result<(), RmctsError> {
+        if let Some(root_parallel) = &mut self.root_parallel {
+            root_parallel.close()?;
+        }
+        for pool in self.pools.iter_mut() {
+            pool.close()?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::rollout::RolloutAggregation;
+    use crate::run::CheckpointMode;
+    use crate::test_util::{make_egraph, make_rules, AstSize};
+    use egg::SymbolLang;
+
+    fn make_tree(
+        expr: &str,
+        args: &MCTSArgs,
+    ) -> (
+        Tree<SymbolLang, (), AstSize>,
+        EgraphEnv<SymbolLang, (), AstSize>,
+    ) {
+        let rules = make_rules();
+        let (egraph, root) = make_egraph(expr);
+        let tree = Tree::new(args, egraph.clone(), &[root], rules.clone(), AstSize);
+        let mut env = EgraphEnv::from_args(egraph, vec![root], rules, AstSize, args).unwrap();
+        env.reset();
+        (tree, env)
+    }
+
+    #[test]
+    fn test_replay_checkpoint_rebuilds_the_egraph() {
+        let args = MCTSArgs {
+            checkpoint_mode: CheckpointMode::Replay,
+            ..Default::default()
+        };
+        let (mut tree, mut env) = make_tree("(+ (+ x 0) 0)", &args);
+        tree.close().unwrap();
+        let start = env.checkpoint();
+        env.step(0).unwrap();
+        env.step(1).unwrap();
+        let ckpt = env.checkpoint();
+        assert!(ckpt.egraph.is_none());
+        assert_eq!(ckpt.history, vec![0, 1]);
+        let stepped = env.snapshot().egraph.unwrap();
+        let best = env.get_best_exprs().unwrap();
+
+        env.restore(start).unwrap();
+        let initial = env.snapshot().egraph.unwrap();
+        // add-0 merges both sums into `x`
+        assert!(initial.number_of_classes() > stepped.number_of_classes());
+
+        env.restore(ckpt.clone()).unwrap();
+        let replayed = env.snapshot().egraph.unwrap();
+        assert_eq!(
+            replayed.total_number_of_nodes(),
+            stepped.total_number_of_nodes()
+        );
+        assert_eq!(replayed.number_of_classes(), stepped.number_of_classes());
+        assert_eq!(env.get_best_exprs().unwrap(), best);
+        assert_eq!(env.checkpoint().last_cost, ckpt.last_cost);
+    }
+
+    #[test]
+    fn test_every_simulation_reaches_the_root() {
+        // with two expansion workers, both root actions are soon reserved while no
+        // child is finished; later simulations must wait rather than count as done
+        let args = MCTSArgs {
+            budget: 8,
+            expansion_worker_num: 2,
+            simulation_worker_num: 2,
+            seed: Some(0),
+            deterministic: true,
+            ..Default::default()
+        };
+        let (mut tree, env) = make_tree("(+ x 0)", &args);
+        tree.search(&env).unwrap();
+        assert_eq!(tree.simulation_count, args.budget);
+        assert_eq!(tree.root_node.borrow().visit_count(), args.budget);
+        tree.close().unwrap();
+    }
+
+    #[test]
+    fn test_timed_out_simulations_complete() {
+        let args = MCTSArgs {
+            budget: 8,
+            simulation_worker_num: 2,
+            task_timeout: Some(Duration::from_nanos(1)),
+            ..Default::default()
+        };
+        let (mut tree, env) = make_tree("(+ 0 (+ x 0))", &args);
+        tree.search(&env).unwrap();
+        assert!(tree.timed_out_count > 0);
+        assert_eq!(tree.simulation_count, args.budget);
+        tree.close().unwrap();
+    }
+
+    #[test]
+    fn test_unified_pool_starts_queued_expansions_first() {
+        // two workers, two simulations queued ahead of an expansion: the expansion
+        // takes the first worker and its reply is applied first
+        let args = MCTSArgs {
+            expansion_worker_num: 1,
+            simulation_worker_num: 1,
+            unified_pool: true,
+            deterministic: true,
+            ..Default::default()
+        };
+        let (mut tree, env) = make_tree("(+ 0 (+ x 0))", &args);
+        let ckpt = env.checkpoint();
+        tree.ckpts.insert(0, ckpt.clone());
+        tree.root_node = Node::new(env.get_action_space(), 0, 1.0, true, None);
+        tree.global_saving_idx = 1;
+        for task_idx in 0..2 {
+            tree.root_node.borrow_mut().update_history(task_idx, 1, 0.0);
+            tree.simulation_tasks
+                .insert(task_idx, SimTask::new(ckpt.clone(), 1, 0, false, 0));
+            tree.simulation_nodes_copy
+                .insert(task_idx, Rc::clone(&tree.root_node));
+            tree.pending_simulation_tasks.push_back(task_idx);
+        }
+        tree.root_node.borrow_mut().mark_expanding(0);
+        tree.expansion_tasks.insert(
+            2,
+            ExpTask {
+                checkpoint_data: ckpt,
+                action: 0,
+                d1: PhantomData,
+                d2: PhantomData,
+            },
+        );
+        tree.expansion_nodes_copy
+            .insert(2, Rc::clone(&tree.root_node));
+        tree.pending_expansion_tasks.push_back(2);
+
+        assert!(tree.wait_for_task().unwrap());
+        // the expansion came back while simulation 0 still runs and 1 waits
+        assert!(tree.expansion_tasks.is_empty());
+        assert_eq!(tree.pending_simulation_tasks, [1, 2]);
+        assert!(matches!(
+            tree.pools[0].get_complete_task(),
+            Ok(Reply::DoneSimulation(0, _))
+        ));
+        tree.close().unwrap();
+    }
+
+    #[test]
+    fn test_rollout_returns_are_aggregated() {
+        for (aggregation, expected) in [
+            (RolloutAggregation::Mean, 0.5 + 2.0),
+            (RolloutAggregation::Max, 0.5 + 3.0),
+        ] {
+            let args = MCTSArgs {
+                rollouts_per_leaf: 3,
+                rollout_aggregation: aggregation,
+                ..Default::default()
+            };
+            let (mut tree, env) = make_tree("(+ 0 (+ x 0))", &args);
+            tree.root_node = Node::new(env.get_action_space(), 0, 1.0, true, None);
+            tree.root_node.borrow_mut().update_history(0, 1, 0.5);
+            tree.simulation_tasks
+                .insert(0, SimTask::new(env.checkpoint(), 1, 1, false, 0));
+            tree.simulation_nodes_copy
+                .insert(0, Rc::clone(&tree.root_node));
+
+            for accu_reward in [1.0, 3.0] {
+                tree.update_simulation(Reply::DoneSimulation(0, accu_reward))
+                    .unwrap();
+            }
+            // the leaf is backed up once, after its last rollout
+            assert!(tree.root_node.borrow().children[1].is_none());
+            tree.update_simulation(Reply::DoneSimulation(0, 2.0))
+                .unwrap();
+            let stats = tree.root_node.borrow().children_stats()[1].clone().unwrap();
+            assert_eq!(stats.complete_visit_count, 1);
+            assert_eq!(stats.q_sum, expected);
+            assert_eq!(tree.simulation_count, 1);
+            tree.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reused_subtree_keeps_its_visits() {
+        let args = MCTSArgs {
+            budget: 12,
+            simulation_worker_num: 2,
+            seed: Some(0),
+            deterministic: true,
+            reuse_tree: true,
+            ..Default::default()
+        };
+        let (mut tree, mut env) = make_tree("(+ 0 (+ x 0))", &args);
+        let stats = tree.search(&env).unwrap();
+        let action = best_mean_action(&stats).unwrap();
+        let child = tree.root_node.borrow().children[action].clone().unwrap();
+        let visits = child.borrow().visit_count();
+        assert!(visits > 0);
+
+        env.step(action).unwrap();
+        tree.advance_root(action);
+        assert!(Rc::ptr_eq(tree.next_root.as_ref().unwrap(), &child));
+        tree.search(&env).unwrap();
+        assert!(Rc::ptr_eq(&tree.root_node, &child));
+        assert!(tree.simulation_count > 0);
+        assert_eq!(
+            tree.root_node.borrow().visit_count(),
+            visits + tree.simulation_count
+        );
+        tree.close().unwrap();
+    }
+
+    #[test]
+    fn test_search_keeps_no_stale_history() {
+        // out of time, pending tasks are dropped; the next steps reuse the subtree
+        let args = MCTSArgs {
+            budget: 0,
+            planning_time_limit: Some(Duration::from_millis(50)),
+            expansion_worker_num: 2,
+            simulation_worker_num: 2,
+            reuse_tree: true,
+            ..Default::default()
+        };
+        let (mut tree, mut env) = make_tree("(+ 0 (+ x (+ 0 (+ x 0))))", &args);
+        for _ in 0..3 {
+            let stats = tree.search(&env).unwrap();
+            let mut checkpoints = std::collections::HashSet::new();
+            let mut stack = vec![Rc::clone(&tree.root_node)];
+            while let Some(node) = stack.pop() {
+                let node = node.borrow();
+                assert_eq!(node.pending_history(), 0);
+                checkpoints.insert(node.checkpoint_idx);
+                stack.extend(node.children.iter().flatten().cloned());
+            }
+            let len = tree.ckpts.len();
+            tree.ckpts.retain(|idx| checkpoints.contains(&idx));
+            assert_eq!(tree.ckpts.len(), len);
+
+            let action = best_mean_action(&stats).unwrap();
+            let (_, _, done, _) = env.step(action).unwrap();
+            if done {
+                break;
+            }
+            tree.advance_root(action);
+        }
+        tree.close().unwrap();
+    }
 
     #[test]
     fn test_if_map_take_ownership() {
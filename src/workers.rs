@@ -1,22 +1,34 @@
 use crate::eg_env::{Ckpt, EgraphEnv};
+use crate::error::RmctsError;
 // use crate::env::Env;
+use crate::reward::IntoReward;
+use crate::rollout::{RolloutContext, StateSummary};
+use crate::run::MCTSArgs;
 use crate::tree::{ExpTask, SimTask};
 
 #[allow(unused_imports)]
 use egg::{
     Analysis, CostFunction, EGraph, Id, Language, LpCostFunction, RecExpr, Rewrite, StopReason,
 };
-use rand::Rng;
-use std::sync::mpsc;
+use log::trace;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub enum Message<L, N>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
 {
     Exit,
     #[allow(unused_variables)]
@@ -27,143 +39,270 @@ where
 
 pub enum Reply<L, N>
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
 {
     OK,
     DoneExpansion(usize, (), f32, bool, bool, Option<Ckpt<L, N>>, u32, u32),
     DoneSimulation(u32, f32),
+    /// The simulation was cancelled or hit `task_timeout`; carries the return of
+    /// the steps taken so far, truncated as at `max_sim_step`.
+    TimedOutSimulation(u32, f32),
+    Failed(RmctsError),
 }
 
-pub fn worker_loop<L, N, CF>(
+pub fn worker_loop<L, N, CF, F>(
     id: usize,
-    gamma: f32,
-    max_sim_step: u32,
-    verbose: bool,
-    egraph: EGraph<L, N>,
-    root_id: Id,
-    rules: Vec<Rewrite<L, N>>,
-    cf: CF,
-    lp_extract: bool,
-    node_limit: usize,
-    time_limit: usize,
-) -> (
-    thread::JoinHandle<()>,
-    mpsc::Sender<Message<L, N>>,
-    mpsc::Receiver<Reply<L, N>>,
-)
+    args: MCTSArgs,
+    cancel: Arc<AtomicBool>,
+    // shared by every worker of a pool, replies are tagged with the worker id
+    tx2: mpsc::Sender<(usize, Reply<L, N>)>,
+    // builds the worker's env on its own thread
+    make_env: F,
+) -> (thread::JoinHandle<()>, mpsc::Sender<Message<L, N>>)
 where
-    L: Language + 'static + egg::FromOp + std::marker::Send,
-    N: Analysis<L> + Clone + 'static + std::default::Default + std::marker::Send,
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
     N::Data: Clone,
-    <N as Analysis<L>>::Data: Send,
+    <N as Analysis<L>>::Data: Send + Sync,
     CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
-    usize: From<<CF as CostFunction<L>>::Cost>,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+    F: FnOnce() -> Result<EgraphEnv<L, N, CF>, RmctsError> + Send + 'static,
 {
     let (tx, rx) = mpsc::channel();
-    let (tx2, rx2) = mpsc::channel();
     let handle = thread::spawn(move || {
         // make env
         // let mut env = Env::new(expr, rules, node_limit, time_limit);
-        let mut env = EgraphEnv::new(
-            egraph, root_id, rules, cf, lp_extract, node_limit, time_limit,
-        );
+        let mut env = match make_env() {
+            Ok(env) => env,
+            Err(e) => {
+                // the planner sees the failure on its next send/recv
+                let _ = tx2.send((id, Reply::Failed(e)));
+                return;
+            }
+        };
         env.reset();
         // worker loop
         loop {
-            let message = rx.recv().unwrap();
-            match message {
+            let message = match rx.recv() {
+                Ok(message) => message,
+                Err(_) => break, // planner is gone
+            };
+            let reply = match message {
                 Message::Exit => {
                     // println!("Worker {} Exit!", id);
                     break;
                 }
 
                 Message::Expansion(exp_task, global_saving_idx, task_idx) => {
-                    if verbose {
-                        println!("Worker {} Expansion!", id);
-                    }
+                    trace!("worker {} expansion", id);
                     // expand one step
-                    env.restore(exp_task.checkpoint_data);
-                    let expand_action = exp_task.shallow_copy_node.select_expansion_action();
-                    let (next_state, reward, done, info) = env.step(expand_action);
-                    let new_checkpoint_data = if done { None } else { Some(env.checkpoint()) };
-
-                    // saturated means this action doesn't match any enode
-                    // so we shouldn't select again!
-                    let mut child_saturated = false;
-                    match info.report.stop_reason {
-                        StopReason::Saturated => child_saturated = true,
-                        _ => (),
-                    }
-
-                    // reply
-                    tx2.send(Reply::DoneExpansion(
-                        expand_action,
-                        next_state,
-                        reward,
-                        done,
-                        child_saturated,
-                        new_checkpoint_data,
-                        global_saving_idx,
-                        task_idx,
-                    ))
-                    .unwrap();
+                    let expand_action = exp_task.action;
+                    env.restore(exp_task.checkpoint_data)
+                        .and_then(|_| env.step(expand_action))
+                        .map(|(next_state, reward, done, info)| {
+                            let new_checkpoint_data =
+                                if done { None } else { Some(env.checkpoint()) };
+
+                            // saturated means this action doesn't match any enode
+                            // so we shouldn't select again!
+                            let child_saturated =
+                                matches!(info.report.stop_reason, StopReason::Saturated);
+
+                            Reply::DoneExpansion(
+                                expand_action,
+                                next_state,
+                                reward,
+                                done,
+                                child_saturated,
+                                new_checkpoint_data,
+                                global_saving_idx,
+                                task_idx,
+                            )
+                        })
                 }
 
                 Message::Simulation(sim_task, task_idx) => {
-                    env.restore(sim_task.checkpoint_data);
                     assert!(sim_task.action_applied);
-
-                    let mut cnt = 0;
-                    let mut _state;
-                    let mut reward;
-                    let mut done = false; // NOTE if already done, then this simulation will not be scheduled
-                    let mut accu_reward = 0.0;
-                    let mut accu_gamma = 1.0;
-                    let mut _info;
-                    // start_state_value = self.get_value(_state) // TODO
-                    let start_state_value = 0.0; // to tune?
-                    let factor = 1.0; //  to tune?
-                    let mut rng = rand::thread_rng();
-
-                    // env loop
-                    while !done {
-                        // random policy rollouts
-                        let action_n = env.get_action_space();
-                        let action = rng.gen_range(0..action_n);
-                        (_state, reward, done, _info) = env.step(action);
-
-                        // timeLimited truncate
-                        if cnt == max_sim_step && !done {
-                            done = true;
-                            // get the final reward
-                            // reward = env.get_reward();
+                    let mut rng = ChaCha8Rng::seed_from_u64(sim_task.seed);
+                    // restoring may replay, which must not be cut short
+                    let result = env.restore(sim_task.checkpoint_data).and_then(|_| {
+                        let deadline = args.task_timeout.map(|timeout| Instant::now() + timeout);
+                        env.set_cancellation(Some(Arc::clone(&cancel)), deadline);
+                        simulate(&mut env, &args, &mut rng)
+                    });
+                    env.set_cancellation(None, None);
+                    result.map(|(accu_reward, timed_out)| {
+                        if timed_out {
+                            Reply::TimedOutSimulation(task_idx, accu_reward)
+                        } else {
+                            Reply::DoneSimulation(task_idx, accu_reward)
                         }
-
-                        accu_reward += reward * accu_gamma;
-                        accu_gamma *= gamma;
-                        cnt += 1;
-                    }
-
-                    //  Use V(s) to stabilize simulation return
-                    accu_reward = accu_reward * factor + start_state_value * (1.0 - factor);
-
-                    // reply
-                    tx2.send(Reply::DoneSimulation(task_idx, accu_reward))
-                        .unwrap();
+                    })
                 }
 
                 Message::Nothing => {
                     // act as random straggler
                     let mut rng = rand::thread_rng();
                     thread::sleep(Duration::from_secs(rng.gen_range(0..5)));
-                    tx2.send(Reply::OK).unwrap();
+                    Ok(Reply::OK)
                 }
+            };
+
+            // reply; a failed worker reports once and exits
+            let failed = reply.is_err();
+            if tx2.send((id, reply.unwrap_or_else(Reply::Failed))).is_err() || failed {
+                break;
             }
         }
     });
 
-    (handle, tx, rx2)
+    (handle, tx)
+}
+
+fn simulate<L, N, CF, R: RngCore>(
+    env: &mut EgraphEnv<L, N, CF>,
+    args: &MCTSArgs,
+    rng: &mut R,
+) -> Result<(f32, bool), RmctsError>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    let mut _state;
+    let mut reward;
+    let mut done = false; // NOTE if already done, then this simulation will not be scheduled
+    let mut timed_out = false;
+    let mut accu_reward = 0.0;
+    let mut accu_gamma = 1.0;
+    let mut _info;
+    let start_state_value = match &args.value_estimator {
+        Some(estimator) => estimator.value(&StateSummary {
+            base_cost: env.base_cost,
+            cost: env.last_cost,
+            egraph_nodes: env.egraph.total_number_of_nodes(),
+            egraph_classes: env.egraph.number_of_classes(),
+        }),
+        None => 0.0,
+    };
+    let factor = args.value_factor;
+
+    let mut ctx = EnvRolloutContext {
+        env,
+        step_count: 0,
+        rng,
+    };
+
+    // env loop
+    while !done {
+        let action = args.rollout_policy.select(&mut ctx)?;
+        (_state, reward, done, _info) = ctx.env.step(action)?;
+
+        // out of time: stop here, owing the reward like a truncation
+        if ctx.env.cancelled() && !done {
+            done = true;
+            timed_out = true;
+            reward += ctx.env.truncation_reward();
+        }
+
+        // timeLimited truncate
+        if ctx.step_count == args.max_sim_step && !done {
+            done = true;
+            // get the final reward
+            reward += ctx.env.truncation_reward();
+        }
+
+        accu_reward += reward * accu_gamma;
+        accu_gamma *= args.gamma;
+        ctx.step_count += 1;
+    }
+
+    //  Use V(s) to stabilize simulation return
+    accu_reward = accu_reward * factor + start_state_value * (1.0 - factor);
+    Ok((accu_reward, timed_out))
+}
+
+/// [`RolloutContext`] over the worker's environment.
+struct EnvRolloutContext<'a, L, N, CF, R>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+{
+    env: &'a mut EgraphEnv<L, N, CF>,
+    step_count: u32,
+    rng: &'a mut R,
+}
+
+impl<'a, L, N, CF, R> RolloutContext for EnvRolloutContext<'a, L, N, CF, R>
+where
+    L: Language + 'static + egg::FromOp + std::marker::Send + std::marker::Sync,
+    N: Analysis<L>
+        + Clone
+        + 'static
+        + std::default::Default
+        + std::marker::Send
+        + std::marker::Sync,
+    N::Data: Clone,
+    <N as Analysis<L>>::Data: Send + Sync,
+    CF: CostFunction<L> + LpCostFunction<L, N> + Clone + std::marker::Send + 'static,
+    <CF as CostFunction<L>>::Cost: IntoReward,
+    R: RngCore,
+{
+    fn action_n(&self) -> usize {
+        self.env.get_action_space()
+    }
+
+    fn step_count(&self) -> u32 {
+        self.step_count
+    }
+
+    fn saturated(&self) -> &[bool] {
+        self.env.saturated()
+    }
+
+    fn legal_actions(&self) -> &[bool] {
+        self.env.legal_actions()
+    }
+
+    fn peek_reward(&mut self, action: usize) -> Result<f32, RmctsError> {
+        // always snapshot, a replay per peek would be far slower
+        let checkpoint = self.env.snapshot();
+        let result = self.env.step(action);
+        self.env.restore(checkpoint)?;
+        result.map(|(_, reward, _, _)| reward)
+    }
+
+    fn rng(&mut self) -> &mut dyn RngCore {
+        self.rng
+    }
 }
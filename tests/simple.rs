@@ -1,5 +1,17 @@
 use egg::*;
-use rmcts::*;
+use rmcts::action::Action;
+use rmcts::error::RmctsError;
+use rmcts::policy::{ChildStats, ProgressiveWidening, Puct, SelectionPolicy, Ucb1Tuned};
+use rmcts::reward::RootCost;
+use rmcts::rollout::{
+    EpsilonGreedy, ProportionalValue, RolloutAggregation, RolloutContext, RolloutPolicy,
+    SkipSaturated, StateSummary, ValueEstimator,
+};
+use rmcts::run::{CheckpointMode, MctsStopReason};
+use rmcts::runner::MctsRunner;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 define_language! {
     enum SimpleLanguage {
@@ -100,3 +112,509 @@ fn simple_test() {
     }
     assert!(find);
 }
+
+#[test]
+fn simple_runner_rejects_zero_sim_workers() {
+    let expr: RecExpr<SimpleLanguage> = "(* 0 42)".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_sim_workers(0)
+        .run(&make_rules());
+    assert!(matches!(result, Err(RmctsError::InvalidConfig(_))));
+}
+
+#[test]
+fn simple_runner() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* 1 foo))".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_budget(8)
+        .with_exp_workers(2)
+        .with_sim_workers(2)
+        .with_iter_limit(5)
+        .run(&make_rules())
+        .unwrap();
+    assert!(result.final_cost <= result.base_cost);
+}
+
+#[test]
+fn simple_runner_time_budget() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* 1 foo))".parse().unwrap();
+    let limit = Duration::from_millis(200);
+    for budget in [0, u32::MAX] {
+        let result = MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(budget)
+            .with_planning_time_limit(limit)
+            .with_sim_workers(2)
+            .with_iter_limit(3)
+            .run(&make_rules())
+            .unwrap();
+        assert!(!result.iterations.is_empty());
+        for stats in result.iterations.iter() {
+            // the limit, not the simulation budget, ends planning; in-flight tasks
+            // are then finished or dropped within a small margin
+            assert!(stats.planning_time >= limit);
+            assert!(stats.planning_time <= limit + Duration::from_millis(500));
+        }
+    }
+}
+
+// a float-valued cost model, e.g. an estimated latency
+#[derive(Debug, Clone)]
+pub struct Latency;
+impl CostFunction<SimpleLanguage> for Latency {
+    type Cost = f64;
+    fn cost<C>(&mut self, enode: &SimpleLanguage, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        let op_cost = match enode {
+            SimpleLanguage::Mul(..) => 2.5,
+            _ => 0.5,
+        };
+        enode.fold(op_cost, |sum, id| sum + costs(id))
+    }
+}
+
+impl LpCostFunction<SimpleLanguage, ()> for Latency {
+    fn node_cost(
+        &mut self,
+        _egraph: &EGraph<SimpleLanguage, ()>,
+        _eclass: Id,
+        enode: &SimpleLanguage,
+    ) -> f64 {
+        match enode {
+            SimpleLanguage::Mul(..) => 2.5,
+            _ => 0.5,
+        }
+    }
+}
+
+#[test]
+fn simple_float_cost() {
+    let expr: RecExpr<SimpleLanguage> = "(* (+ 0 foo) 1)".parse().unwrap();
+    let result = MctsRunner::new(Latency)
+        .with_expr(&expr)
+        .with_budget(8)
+        .with_sim_workers(2)
+        .with_iter_limit(5)
+        .with_cost_threshold(0.0)
+        .run(&make_rules())
+        .unwrap();
+    assert_eq!(result.base_cost, 4.5);
+    assert!(result.final_cost <= result.base_cost);
+}
+
+#[test]
+fn simple_runner_multi_root() {
+    let a: RecExpr<SimpleLanguage> = "(+ 0 (* 1 foo))".parse().unwrap();
+    let b: RecExpr<SimpleLanguage> = "(* (+ 0 foo) 1)".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&a)
+        .with_expr(&b)
+        .with_root_cost(RootCost::SharedDag)
+        .with_budget(8)
+        .with_sim_workers(2)
+        .with_iter_limit(5)
+        .run(&make_rules())
+        .unwrap();
+    assert_eq!(result.best_exprs.len(), 2);
+    assert!(result.final_cost <= result.base_cost);
+}
+
+#[test]
+fn simple_runner_seed_is_reproducible() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let run = || {
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(3)
+            .with_iter_limit(5)
+            .with_cost_threshold(0.0)
+            .with_seed(7)
+            .with_deterministic(true)
+            .run(&make_rules())
+            .unwrap()
+    };
+    let (a, b) = (run(), run());
+    assert_eq!(a.actions, b.actions);
+    assert_eq!(a.final_cost, b.final_cost);
+}
+
+#[test]
+fn simple_runner_rollouts_per_leaf() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    for aggregation in [RolloutAggregation::Mean, RolloutAggregation::Max] {
+        let result = MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(3)
+            .with_rollouts_per_leaf(3, aggregation)
+            .with_iter_limit(5)
+            .with_cost_threshold(0.0)
+            .run(&make_rules())
+            .unwrap();
+        assert!(result.final_cost < result.base_cost);
+    }
+}
+
+#[test]
+fn simple_runner_unified_pool() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let run = |deterministic| {
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(3)
+            .with_unified_pool(true)
+            .with_iter_limit(5)
+            .with_cost_threshold(0.0)
+            .with_seed(7)
+            .with_deterministic(deterministic)
+            .run(&make_rules())
+            .unwrap()
+    };
+    let result = run(false);
+    assert!(result.final_cost < result.base_cost);
+    let (a, b) = (run(true), run(true));
+    assert_eq!(a.actions, b.actions);
+}
+
+#[test]
+fn simple_runner_root_parallel() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let run = || {
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(2)
+            .with_root_parallel(4)
+            .with_iter_limit(5)
+            .with_cost_threshold(0.0)
+            .with_seed(7)
+            .with_deterministic(true)
+            .run(&make_rules())
+            .unwrap()
+    };
+    // the planners finish in any order, their statistics are merged in id order
+    let a = run();
+    assert!(a.final_cost <= a.base_cost);
+    for _ in 0..3 {
+        let b = run();
+        assert_eq!(a.actions, b.actions);
+        assert_eq!(a.final_cost, b.final_cost);
+    }
+}
+
+#[test]
+fn simple_runner_rejects_deterministic_time_budget() {
+    let expr: RecExpr<SimpleLanguage> = "(* 0 42)".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_planning_time_limit(Duration::from_millis(100))
+        .with_deterministic(true)
+        .run(&make_rules());
+    assert!(matches!(result, Err(RmctsError::InvalidConfig(_))));
+}
+
+#[test]
+fn simple_runner_task_timeout() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    // every simulation times out right away and still counts as completed
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_budget(16)
+        .with_sim_workers(2)
+        .with_iter_limit(5)
+        .with_cost_threshold(0.0)
+        .with_task_timeout(Duration::from_nanos(1))
+        .run(&make_rules())
+        .unwrap();
+    assert!(!result.iterations.is_empty());
+    assert!(result.final_cost <= result.base_cost);
+
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_task_timeout(Duration::from_millis(100))
+        .with_deterministic(true)
+        .run(&make_rules());
+    assert!(matches!(result, Err(RmctsError::InvalidConfig(_))));
+}
+
+#[test]
+fn simple_runner_cheap_checkpoints() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let runner = || {
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(2)
+            .with_iter_limit(5)
+            .with_cost_threshold(0.0)
+            .with_seed(7)
+            .with_deterministic(true)
+    };
+    // replayed states match the snapshots, so a deterministic search plans the same
+    let snapshot = runner().run(&make_rules()).unwrap();
+    let replayed = runner()
+        .with_checkpoint_mode(CheckpointMode::Replay)
+        .run(&make_rules())
+        .unwrap();
+    let capped = runner().with_max_snapshots(2).run(&make_rules()).unwrap();
+    for result in [replayed, capped] {
+        assert_eq!(result.actions, snapshot.actions);
+        assert_eq!(result.final_cost, snapshot.final_cost);
+    }
+    assert!(runner().with_max_snapshots(0).run(&make_rules()).is_err());
+}
+
+#[test]
+fn simple_runner_memory_limit() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let runner = || {
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(2)
+            .with_iter_limit(5)
+            .with_cost_threshold(0.0)
+    };
+    let unbounded = runner().run(&make_rules()).unwrap();
+    assert!(unbounded.peak_memory > 0);
+    let limit = unbounded.peak_memory / 8;
+    let bounded = runner()
+        .with_memory_limit(limit)
+        .run(&make_rules())
+        .unwrap();
+    assert!(bounded.peak_memory > 0);
+    assert!(bounded.peak_memory <= limit);
+    // smaller than any e-graph: every snapshot is evicted, one task runs at a time
+    let tiny = runner().with_memory_limit(1).run(&make_rules()).unwrap();
+    assert!(tiny.final_cost <= tiny.base_cost);
+    // the later rollouts of a leaf must not wait on memory its earlier ones released
+    let tiny = runner()
+        .with_memory_limit(1)
+        .with_rollouts_per_leaf(3, RolloutAggregation::Mean)
+        .run(&make_rules())
+        .unwrap();
+    assert!(tiny.final_cost <= tiny.base_cost);
+}
+
+/// Forwards to `policy`, counting the children it scores.
+#[derive(Debug)]
+struct CountingSelection {
+    policy: Arc<dyn SelectionPolicy>,
+    scored: AtomicUsize,
+}
+
+impl SelectionPolicy for CountingSelection {
+    fn score(&self, parent_visits: u32, child: &ChildStats) -> f32 {
+        self.scored.fetch_add(1, Ordering::Relaxed);
+        self.policy.score(parent_visits, child)
+    }
+
+    fn can_widen(&self, visit_count: u32, expanded: usize) -> bool {
+        self.policy.can_widen(visit_count, expanded)
+    }
+
+    fn validate(&self, action_n: usize) -> Result<(), RmctsError> {
+        self.policy.validate(action_n)
+    }
+}
+
+#[test]
+fn simple_runner_selection_policies() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let widened_puct = ProgressiveWidening {
+        policy: Arc::new(Puct {
+            c: 1.5,
+            priors: Some(vec![0.1, 0.1, 0.3, 0.2, 0.3]),
+        }),
+        k: 1.0,
+        alpha: 0.5,
+    };
+    let policies: Vec<Arc<dyn SelectionPolicy>> =
+        vec![Arc::new(Ucb1Tuned { c: 1.0 }), Arc::new(widened_puct)];
+    for policy in policies {
+        let counting = Arc::new(CountingSelection {
+            policy,
+            scored: AtomicUsize::new(0),
+        });
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(2)
+            .with_iter_limit(5)
+            .with_selection_policy(counting.clone())
+            .with_expansion_prob(0.3)
+            .run(&make_rules())
+            .unwrap();
+        assert!(counting.scored.load(Ordering::Relaxed) > 0);
+    }
+}
+
+#[test]
+fn simple_runner_rejects_short_priors() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_selection_policy(Arc::new(Puct {
+            c: 1.5,
+            priors: Some(vec![0.5, 0.5]),
+        }))
+        .run(&make_rules());
+    assert!(matches!(result, Err(RmctsError::InvalidConfig(_))));
+}
+
+/// Forwards to `policy`, counting the rollout steps it picks.
+#[derive(Debug)]
+struct CountingRollout {
+    policy: Arc<dyn RolloutPolicy>,
+    selected: AtomicUsize,
+}
+
+impl RolloutPolicy for CountingRollout {
+    fn select(&self, ctx: &mut dyn RolloutContext) -> Result<usize, RmctsError> {
+        self.selected.fetch_add(1, Ordering::Relaxed);
+        self.policy.select(ctx)
+    }
+}
+
+/// Forwards to `estimator`, counting the states it values.
+#[derive(Debug)]
+struct CountingValue {
+    estimator: ProportionalValue,
+    valued: AtomicUsize,
+}
+
+impl ValueEstimator for CountingValue {
+    fn value(&self, state: &StateSummary) -> f32 {
+        self.valued.fetch_add(1, Ordering::Relaxed);
+        self.estimator.value(state)
+    }
+}
+
+#[test]
+fn simple_runner_rollout_policies() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let policies: Vec<Arc<dyn RolloutPolicy>> = vec![
+        Arc::new(EpsilonGreedy { epsilon: 0.2 }),
+        Arc::new(SkipSaturated),
+    ];
+    for policy in policies {
+        let counting = Arc::new(CountingRollout {
+            policy,
+            selected: AtomicUsize::new(0),
+        });
+        let value = Arc::new(CountingValue {
+            estimator: ProportionalValue { scale: 0.1 },
+            valued: AtomicUsize::new(0),
+        });
+        MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(8)
+            .with_sim_workers(2)
+            .with_iter_limit(5)
+            .with_rollout_policy(counting.clone())
+            .with_value_estimator(value.clone(), 0.8)
+            .run(&make_rules())
+            .unwrap();
+        assert!(counting.selected.load(Ordering::Relaxed) > 0);
+        assert!(value.valued.load(Ordering::Relaxed) > 0);
+    }
+}
+
+#[test]
+fn simple_runner_no_rule_matches() {
+    let expr: RecExpr<SimpleLanguage> = "foo".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_action_mask(true)
+        .run(&make_rules())
+        .unwrap();
+    assert!(result.actions.is_empty());
+    assert!(matches!(
+        result.stop_reason,
+        MctsStopReason::Done(StopReason::Saturated)
+    ));
+}
+
+#[test]
+fn simple_runner_with_action_mask() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* 1 foo))".parse().unwrap();
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_budget(8)
+        .with_sim_workers(2)
+        .with_iter_limit(5)
+        .with_action_mask(true)
+        .run(&make_rules())
+        .unwrap();
+    assert!(result.final_cost <= result.base_cost);
+    // only rules that match are chosen: there is no `(* x 0)` to rewrite
+    for (_, name) in result.actions.iter() {
+        assert_ne!(name, "mul-0");
+    }
+}
+
+#[test]
+fn simple_runner_saturated_root_stops_cleanly() {
+    // every rule saturates quickly, leaving the root with only saturated children
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 1)".parse().unwrap();
+    for action_mask in [false, true] {
+        let result = MctsRunner::new(AstSize)
+            .with_expr(&expr)
+            .with_budget(16)
+            .with_sim_workers(2)
+            .with_iter_limit(20)
+            .with_action_mask(action_mask)
+            .run(&make_rules())
+            .unwrap();
+        assert_eq!(result.final_cost, 1.0);
+        assert!(matches!(
+            result.stop_reason,
+            MctsStopReason::Done(StopReason::Saturated)
+        ));
+    }
+}
+
+#[test]
+fn simple_runner_macro_actions() {
+    let expr: RecExpr<SimpleLanguage> = "(+ 0 (* (+ 1 0) (* foo 1)))".parse().unwrap();
+    let rules = make_rules();
+    let actions = vec![
+        Action::group("commute", &rules, |name| name.starts_with("commute")),
+        Action::group("simplify", &rules, |name| !name.starts_with("commute")),
+        Action::repeat(&rules, 4, 3),
+    ];
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_budget(8)
+        .with_sim_workers(2)
+        .with_iter_limit(5)
+        .with_cost_threshold(0.0)
+        .with_actions(actions)
+        .run(&rules)
+        .unwrap();
+    assert!(result.final_cost <= result.base_cost);
+    for (_, name) in result.actions.iter() {
+        assert!(["commute", "simplify", "mul-1*3"].contains(&name.as_str()));
+    }
+}
+
+#[test]
+fn simple_runner_rejects_unknown_rule_in_action() {
+    let expr: RecExpr<SimpleLanguage> = "(* 0 42)".parse().unwrap();
+    let action = Action {
+        name: "bad".to_string(),
+        rules: vec![7],
+        iter_limit: 1,
+    };
+    let result = MctsRunner::new(AstSize)
+        .with_expr(&expr)
+        .with_actions(vec![action])
+        .run(&make_rules());
+    assert!(matches!(result, Err(RmctsError::InvalidConfig(_))));
+}